@@ -0,0 +1,193 @@
+use std::net::IpAddr;
+use std::ops::RangeInclusive;
+
+use ipnet::IpNet;
+
+use crate::config::AclConfig;
+use crate::error::Error;
+use crate::rules::{domain_has_suffix, parse_cidr, parse_port_range};
+
+/// Loopback, RFC 1918, shared (CGNAT), link-local, multicast, broadcast and
+/// unspecified addresses, their IPv6 counterparts, and the NAT64 and 6to4
+/// prefixes, which can carry any of the IPv4 ones.
+const PRIVATE_NETS: &[&str] = &[
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.168.0.0/16",
+    "224.0.0.0/4",
+    "255.255.255.255/32",
+    "::/128",
+    "::1/128",
+    "64:ff9b::/96",
+    "2002::/16",
+    "fc00::/7",
+    "fe80::/10",
+    "ff00::/8",
+];
+
+/// Destination access control of an outgoing. Domain and port checks are done
+/// on the request, IP checks on each resolved address, so the address that
+/// passed is the one that gets connected to.
+#[derive(Debug)]
+pub struct Acl {
+    allow_cidr: Vec<IpNet>,
+    deny_cidr: Vec<IpNet>,
+    allow_port: Vec<RangeInclusive<u16>>,
+    deny_port: Vec<RangeInclusive<u16>>,
+    allow_domain: Vec<String>,
+    deny_domain: Vec<String>,
+}
+
+impl Acl {
+    pub fn from_cfg(conf: &AclConfig) -> Result<Self, Error> {
+        let cidrs = |v: &[String]| {
+            v.iter()
+                .map(|c| parse_cidr(c))
+                .collect::<Result<Vec<_>, _>>()
+        };
+        let ports = |v: &[String]| {
+            v.iter()
+                .map(|p| parse_port_range(p))
+                .collect::<Result<Vec<_>, _>>()
+        };
+        let mut deny_cidr = cidrs(&conf.deny_cidr)?;
+        if conf.block_private {
+            deny_cidr.extend(PRIVATE_NETS.iter().map(|n| n.parse::<IpNet>().unwrap()));
+        }
+        Ok(Acl {
+            allow_cidr: cidrs(&conf.allow_cidr)?,
+            deny_cidr,
+            allow_port: ports(&conf.allow_port)?,
+            deny_port: ports(&conf.deny_port)?,
+            allow_domain: conf.allow_domain.clone(),
+            deny_domain: conf.deny_domain.clone(),
+        })
+    }
+
+    pub fn check_port(&self, port: u16) -> bool {
+        (self.allow_port.is_empty() || self.allow_port.iter().any(|r| r.contains(&port)))
+            && !self.deny_port.iter().any(|r| r.contains(&port))
+    }
+
+    pub fn check_domain(&self, domain: &str) -> bool {
+        self.allow_domain
+            .iter()
+            .any(|s| domain_has_suffix(domain, s))
+            || !self
+                .deny_domain
+                .iter()
+                .any(|s| domain_has_suffix(domain, s))
+    }
+
+    pub fn check_ip(&self, ip: IpAddr) -> bool {
+        // ::ffff:127.0.0.1 must not sneak past the IPv4 entries
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            v4 => v4,
+        };
+        self.allow_cidr.iter().any(|n| n.contains(&ip))
+            || !self.deny_cidr.iter().any(|n| n.contains(&ip))
+    }
+}
+
+#[cfg(test)]
+mod test_acl {
+    use super::*;
+
+    fn strings(v: &[&str]) -> Vec<String> {
+        v.iter().map(|s| s.to_string()).collect()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn private_addresses() {
+        let open = Acl::from_cfg(&AclConfig {
+            block_private: false,
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(open.check_ip(ip("10.0.0.1")));
+        let acl = Acl::from_cfg(&AclConfig::default()).unwrap();
+        for denied in [
+            "10.0.0.1",
+            "100.64.1.1",
+            "127.0.0.1",
+            "169.254.169.254",
+            "224.0.0.251",
+            "255.255.255.255",
+            "::1",
+            "::ffff:127.0.0.1",
+            "::ffff:192.168.1.1",
+            "64:ff9b::a00:1",
+            "2002:a00:1::",
+            "fd00::1",
+            "ff02::1",
+        ] {
+            assert!(!acl.check_ip(ip(denied)), "{} allowed", denied);
+        }
+        for allowed in [
+            "93.184.216.34",
+            "100.128.0.1",
+            "::ffff:93.184.216.34",
+            "2001:db8::1",
+        ] {
+            assert!(acl.check_ip(ip(allowed)), "{} denied", allowed);
+        }
+    }
+
+    #[test]
+    fn allow_overrides_deny() {
+        let acl = Acl::from_cfg(&AclConfig {
+            allow_cidr: strings(&["192.168.1.0/24"]),
+            deny_cidr: strings(&["203.0.113.0/24"]),
+            allow_domain: strings(&["good.example.com"]),
+            deny_domain: strings(&["example.com"]),
+            block_private: true,
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(acl.check_ip(ip("192.168.1.10")));
+        assert!(acl.check_ip(ip("::ffff:192.168.1.10")));
+        assert!(!acl.check_ip(ip("192.168.2.10")));
+        assert!(!acl.check_ip(ip("203.0.113.5")));
+        assert!(!acl.check_ip(ip("::ffff:203.0.113.5")));
+        assert!(acl.check_domain("good.example.com"));
+        assert!(acl.check_domain("www.good.example.com"));
+        assert!(!acl.check_domain("example.com"));
+        assert!(!acl.check_domain("WWW.Example.COM."));
+        assert!(acl.check_domain("notexample.com"));
+    }
+
+    #[test]
+    fn ports() {
+        let acl = Acl::from_cfg(&AclConfig {
+            allow_port: strings(&["80", "443", "8000-8999"]),
+            deny_port: strings(&["8080"]),
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(acl.check_port(443));
+        assert!(acl.check_port(8443));
+        assert!(!acl.check_port(8080));
+        assert!(!acl.check_port(22));
+        let deny_only = Acl::from_cfg(&AclConfig {
+            deny_port: strings(&["25"]),
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(deny_only.check_port(22));
+        assert!(!deny_only.check_port(25));
+        assert!(Acl::from_cfg(&AclConfig {
+            deny_port: strings(&["100-1"]),
+            ..Default::default()
+        })
+        .is_err());
+    }
+}
@@ -0,0 +1,219 @@
+//! Relay buffers, shared by all connections and bounded in total. Once the
+//! ceiling is reached, new connections wait for buffers to come back rather
+//! than allocating more. Buffers that stay unused are freed again.
+
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::config::BufferConfig;
+use crate::error::Error;
+
+/// Returned buffers unused for this long are freed
+const FREE_AFTER: Duration = Duration::from_secs(30);
+/// How long a connection waits for relay buffers before it is turned down
+pub const BUFFER_WAIT: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+pub struct BufferPool {
+    size: usize,
+    /// One permit per buffer that may exist
+    permits: Arc<Semaphore>,
+    /// Returned buffers and when, the most recent last
+    free: Mutex<Vec<(Vec<u8>, Instant)>>,
+    max: usize,
+    in_use: AtomicU64,
+    allocated: AtomicU64,
+    waits: AtomicU64,
+}
+
+impl BufferPool {
+    pub fn from_cfg(conf: &BufferConfig) -> Result<Self, Error> {
+        let max = conf.max_memory / conf.size.max(1);
+        if conf.size == 0 || max < 2 {
+            Err(Error::from_description(
+                "buffers.max_memory must hold at least two buffers of buffers.size",
+            ))?
+        }
+        Ok(BufferPool {
+            size: conf.size,
+            permits: Arc::new(Semaphore::new(max)),
+            free: Mutex::new(vec![]),
+            max,
+            in_use: AtomicU64::new(0),
+            allocated: AtomicU64::new(0),
+            waits: AtomicU64::new(0),
+        })
+    }
+
+    fn take(self: &Arc<Self>, permit: OwnedSemaphorePermit) -> PooledBuf {
+        let buf = self.free.lock().unwrap().pop().map_or_else(
+            || {
+                self.allocated.fetch_add(1, Ordering::Relaxed);
+                vec![0; self.size]
+            },
+            |(buf, _)| buf,
+        );
+        self.in_use.fetch_add(1, Ordering::Relaxed);
+        PooledBuf {
+            buf,
+            pool: self.clone(),
+            _permit: permit,
+        }
+    }
+
+    /// Two buffers, one for each direction of a connection. Both are taken
+    /// at once, so connections never hold one while waiting for another.
+    pub async fn get_pair(self: &Arc<Self>) -> (PooledBuf, PooledBuf) {
+        let mut permits = match self.permits.clone().try_acquire_many_owned(2) {
+            Ok(permits) => permits,
+            Err(_) => {
+                self.waits.fetch_add(1, Ordering::Relaxed);
+                self.permits
+                    .clone()
+                    .acquire_many_owned(2)
+                    .await
+                    .expect("buffer pool semaphore is never closed")
+            }
+        };
+        let second = permits.split(1).unwrap();
+        (self.take(permits), self.take(second))
+    }
+
+    /// Frees the buffers that were returned more than `FREE_AFTER` before
+    /// `now`
+    fn release_idle(&self, now: Instant) {
+        let mut free = self.free.lock().unwrap();
+        let idle = free
+            .iter()
+            .take_while(|(_, returned)| now.duration_since(*returned) > FREE_AFTER)
+            .count();
+        free.drain(..idle);
+        self.allocated.fetch_sub(idle as u64, Ordering::Relaxed);
+    }
+
+    /// Frees idle buffers every `FREE_AFTER`, so memory goes back down after
+    /// a peak
+    pub async fn run(self: Arc<Self>) {
+        let mut ticker = tokio::time::interval(FREE_AFTER);
+        loop {
+            ticker.tick().await;
+            self.release_idle(Instant::now());
+        }
+    }
+
+    pub fn summary(&self) -> String {
+        let get = |c: &AtomicU64| c.load(Ordering::Relaxed);
+        format!(
+            "buffers in use {} allocated {} of {} ({} bytes each), waits {}",
+            get(&self.in_use),
+            get(&self.allocated),
+            self.max,
+            self.size,
+            get(&self.waits)
+        )
+    }
+}
+
+/// Where a relay gets its buffers: a pair reserved before the client was
+/// told it is connected, or the pool, in case splice turns out not to work
+pub enum Buffers<'a> {
+    Reserved((PooledBuf, PooledBuf)),
+    Pool(&'a Arc<BufferPool>),
+}
+
+impl Buffers<'_> {
+    /// Waits up to `BUFFER_WAIT` for a pair from the pool
+    pub async fn pair(self) -> Result<(PooledBuf, PooledBuf), Error> {
+        match self {
+            Buffers::Reserved(pair) => Ok(pair),
+            Buffers::Pool(pool) => tokio::time::timeout(BUFFER_WAIT, pool.get_pair())
+                .await
+                .map_err(|_| Error::from_description("no relay buffers free")),
+        }
+    }
+}
+
+/// A buffer that goes back to its pool when dropped
+#[derive(Debug)]
+pub struct PooledBuf {
+    buf: Vec<u8>,
+    pool: Arc<BufferPool>,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Deref for PooledBuf {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        &self.buf
+    }
+}
+impl DerefMut for PooledBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buf
+    }
+}
+
+impl Drop for PooledBuf {
+    fn drop(&mut self) {
+        self.pool.in_use.fetch_sub(1, Ordering::Relaxed);
+        let buf = std::mem::take(&mut self.buf);
+        self.pool.free.lock().unwrap().push((buf, Instant::now()));
+    }
+}
+
+#[cfg(test)]
+mod test_bufpool {
+    use super::*;
+
+    #[tokio::test]
+    async fn waits_at_ceiling_and_reuses() {
+        let pool = Arc::new(
+            BufferPool::from_cfg(&BufferConfig {
+                size: 1024,
+                max_memory: 4096,
+            })
+            .unwrap(),
+        );
+        let first = pool.get_pair().await;
+        let second = pool.get_pair().await;
+        assert_eq!(first.0.len(), 1024);
+        // All four buffers are out
+        let third = tokio::time::timeout(Duration::from_millis(50), pool.get_pair()).await;
+        assert!(third.is_err());
+        drop(first);
+        let third = pool.get_pair().await;
+        drop((second, third));
+        assert_eq!(pool.allocated.load(Ordering::Relaxed), 4);
+        assert_eq!(pool.in_use.load(Ordering::Relaxed), 0);
+        assert_eq!(pool.waits.load(Ordering::Relaxed), 1);
+        assert!(BufferPool::from_cfg(&BufferConfig {
+            size: 1024,
+            max_memory: 1024
+        })
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn frees_idle_buffers() {
+        let pool = Arc::new(BufferPool::from_cfg(&Default::default()).unwrap());
+        let first = pool.get_pair().await;
+        let second = pool.get_pair().await;
+        drop(first);
+        let returned = Instant::now();
+        pool.release_idle(returned);
+        assert_eq!(pool.allocated.load(Ordering::Relaxed), 4);
+        std::thread::sleep(Duration::from_millis(10));
+        drop(second);
+        // Only the pair returned first has been idle for long enough
+        pool.release_idle(returned + FREE_AFTER + Duration::from_millis(5));
+        assert_eq!(pool.allocated.load(Ordering::Relaxed), 2);
+        assert_eq!(pool.free.lock().unwrap().len(), 2);
+        // Taken from what is left
+        let _third = pool.get_pair().await;
+        assert_eq!(pool.allocated.load(Ordering::Relaxed), 2);
+    }
+}
@@ -1,13 +1,111 @@
-use log::error;
+use log::{error, info};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
 
 use crate::{
-    config::OutgoingConfig,
-    connection::bicopy,
-    incoming::IncomingClient,
-    outgoing::{get_outgoing, Outgoing, OutgoingError},
+    bufpool::{BufferPool, Buffers, BUFFER_WAIT},
+    config::IncomingConfig,
+    conn_limit::ConnLimiter,
+    connection::{bicopy, Connection, Progress},
+    error::Error,
+    fake_ip::FakeIpPool,
+    incoming::{Command, IncomingClient},
+    outgoing::{check_implemented, get_outgoing, Outgoing, OutgoingEntry, OutgoingError},
+    quota::Quotas,
+    ratelimit::{IncomingLimit, RateLimiter},
     req_addr::ReqAddr,
+    resolver::Resolver,
+    rules::Router,
+    stats::{CloseReason, ConnRecord, Stats},
 };
 
+/// State shared by the clients of every incoming
+pub struct ClientContext {
+    pub router: Router,
+    pub resolver: Arc<Resolver>,
+    pub fake_ip: Option<FakeIpPool>,
+    /// Closes clients that have not made their request by then
+    pub handshake_deadline: Option<Duration>,
+    /// Closes established connections that carry no data for this long
+    pub idle_timeout: Option<Duration>,
+    pub stats: Arc<Stats>,
+    pub rate_limiter: RateLimiter,
+    pub quotas: Option<Arc<Quotas>>,
+    pub buffers: Arc<BufferPool>,
+    pub conn_limiter: Arc<ConnLimiter>,
+    /// Set when the connections still open are to be closed
+    pub closing: watch::Receiver<bool>,
+}
+
+impl ClientContext {
+    /// Turns a request for a fake IP back into one for the domain it was
+    /// handed out for.
+    fn restore_fake_ip(&self, req: ReqAddr) -> Result<ReqAddr, OutgoingError> {
+        match (&self.fake_ip, &req) {
+            (Some(pool), ReqAddr::IP(addr)) if pool.contains(addr.ip()) => {
+                match pool.lookup(addr.ip()) {
+                    Some(domain) => Ok(ReqAddr::Domain(domain, addr.port())),
+                    None => Err(OutgoingError::HostUnreachable(Error::from_description(
+                        &format!("fake ip {} is not mapped", addr.ip()),
+                    ))),
+                }
+            }
+            _ => Ok(req),
+        }
+    }
+}
+
+#[cfg(test)]
+impl ClientContext {
+    /// No timeouts, limits, quotas or fake IPs
+    pub fn for_test(router: Router) -> Self {
+        ClientContext {
+            router,
+            resolver: Arc::new(Resolver::default()),
+            fake_ip: None,
+            handshake_deadline: None,
+            idle_timeout: None,
+            stats: Arc::new(Stats::default()),
+            rate_limiter: RateLimiter::from_cfg(&Default::default()),
+            quotas: None,
+            buffers: Arc::new(BufferPool::from_cfg(&Default::default()).unwrap()),
+            conn_limiter: Arc::new(ConnLimiter::from_cfg(Default::default())),
+            closing: watch::channel(false).1,
+        }
+    }
+}
+
+/// Settings of one incoming that apply to all of its clients
+pub struct IncomingContext {
+    pub name: String,
+    /// Takes the place of the router when set
+    pub outgoing: Option<Arc<OutgoingEntry>>,
+    pub rate_limit: IncomingLimit,
+}
+
+impl IncomingContext {
+    pub fn from_cfg(conf: &IncomingConfig, router: &Router) -> Result<Self, Error> {
+        let outgoing = match &conf.outgoing {
+            Some(name) => Some(router.outgoing(name).cloned().ok_or_else(|| {
+                Error::from_description(&format!(
+                    "incoming {} refers to unknown outgoing {}",
+                    conf.name, name
+                ))
+            })?),
+            None => None,
+        };
+        if let Some(entry) = &outgoing {
+            check_implemented(&entry.conf)?;
+        }
+        Ok(IncomingContext {
+            name: conf.name.clone(),
+            outgoing,
+            rate_limit: IncomingLimit::from_cfg(&conf.rate_limit),
+        })
+    }
+}
+
 async fn abort(client: impl IncomingClient + Send, error: OutgoingError, req: ReqAddr) {
     client
         .abort(error, req)
@@ -15,27 +113,281 @@ async fn abort(client: impl IncomingClient + Send, error: OutgoingError, req: Re
         .unwrap_or_else(|e| error!("handle_request error: {}", e));
 }
 
-async fn process_request(mut client: impl IncomingClient + Send, o: impl Outgoing, r: ReqAddr) {
-    match o.process_request(r.clone()).await {
-        Ok(o) => match client.ready_for_connect(r).await {
-            Ok(i) => match bicopy(i, o).await {
-                Err(e) => error!("error during transfer: {}", e),
-                _ => (),
-            },
-            Err(e) => error!("can't get ready stream: {}", e),
-        },
+/// Resolves once `closing` is set; never if its sender is gone without
+/// setting it
+pub(crate) async fn closed(mut closing: watch::Receiver<bool>) {
+    while !*closing.borrow() {
+        if closing.changed().await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+/// How often the traffic of a connection is passed on while it relays
+const METER_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Passes the traffic in `progress` on to the stats every `every`, and
+/// resolves when the quota of `user` is used up
+async fn meter(
+    ctx: &ClientContext,
+    user: Option<&str>,
+    progress: &Progress,
+    every: Duration,
+) -> CloseReason {
+    let mut ticker = tokio::time::interval(every);
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        ctx.stats.transferred(user, progress.unreported());
+        if let Some(quotas) = &ctx.quotas {
+            if let Err(e) = quotas.check(user) {
+                info!("closing connection: {}", e);
+                return CloseReason::QuotaExceeded;
+            }
+        }
+    }
+}
+
+async fn process_request(
+    mut client: impl IncomingClient + Send,
+    o: impl Outgoing,
+    r: ReqAddr,
+    ctx: &ClientContext,
+    incoming: &IncomingContext,
+    outgoing: &str,
+) {
+    let started = Instant::now();
+    let o = match o.process_request(r.clone(), client.info()).await {
+        Ok(o) => o,
+        Err(e) => {
+            ctx.stats.failed();
+            return abort(client, e, r).await;
+        }
+    };
+    // Buffers are taken before the client is told it is connected, so that
+    // a request can still be turned down when none come free
+    let splice = cfg!(target_os = "linux") && client.is_tcp() && o.as_tcp().is_some();
+    let buffers = if splice {
+        Buffers::Pool(&ctx.buffers)
+    } else {
+        match tokio::time::timeout(BUFFER_WAIT, ctx.buffers.get_pair()).await {
+            Ok(pair) => Buffers::Reserved(pair),
+            Err(_) => {
+                ctx.stats.failed();
+                let e = Error::from_description("no relay buffers free");
+                return abort(client, OutgoingError::GeneralFailure(e), r).await;
+            }
+        }
+    };
+    match client
+        .ready_for_connect(o.l_addr().unwrap_or_default())
+        .await
+    {
+        Ok(i) => {
+            ctx.stats.opened();
+            let limits = ctx.rate_limiter.limits(&incoming.rate_limit, client.user());
+            let progress = Progress::new();
+            let user = client.user();
+            let stop = async {
+                tokio::select! {
+                    close = meter(ctx, user, &progress, METER_INTERVAL) => close,
+                    _ = closed(ctx.closing.clone()) => CloseReason::Shutdown,
+                }
+            };
+            let close = bicopy(i, o, ctx.idle_timeout, &limits, buffers, &progress, stop).await;
+            ctx.stats.transferred(client.user(), progress.unreported());
+            let transfer = progress.finish(close);
+            let record = ConnRecord::new(
+                &incoming.name,
+                outgoing,
+                client.user(),
+                client.peer_addr(),
+                r,
+                started,
+                transfer,
+            );
+            ctx.stats.record(&record);
+        }
+        Err(e) => {
+            ctx.stats.failed();
+            error!("can't get ready stream: {}", e)
+        }
+    }
+}
+
+async fn resolve_request(
+    client: impl IncomingClient + Send,
+    o: impl Outgoing,
+    cmd: Command,
+    r: ReqAddr,
+) {
+    let answer = match (cmd, &r) {
+        (Command::Resolve, ReqAddr::Domain(..)) => o.resolve(r.clone()).await.and_then(|ips| {
+            ips.first()
+                .map(|ip| ReqAddr::from_addr((*ip, 0).into()))
+                .ok_or_else(|| {
+                    OutgoingError::HostUnreachable(Error::from_description("no address"))
+                })
+        }),
+        (Command::ResolvePtr, ReqAddr::IP(addr)) => o
+            .resolve_ptr(addr.ip())
+            .await
+            .map(|name| ReqAddr::Domain(name, 0)),
+        // A literal address, or a fake IP that was already mapped back
+        (_, ReqAddr::IP(addr)) => Ok(ReqAddr::from_addr((addr.ip(), 0).into())),
+        (_, ReqAddr::Domain(domain, _)) => Ok(ReqAddr::Domain(domain.clone(), 0)),
+    };
+    match answer {
+        Ok(answer) => {
+            info!("{} resolved to {}", r, answer);
+            client
+                .resolved(answer)
+                .await
+                .unwrap_or_else(|e| error!("handle_request error: {}", e))
+        }
         Err(e) => abort(client, e, r).await,
     }
 }
 
-pub async fn handle_client(mut client: impl IncomingClient + Send, outgoing_cfg: OutgoingConfig) {
-    match client.next_request().await {
-        Ok(r) => match get_outgoing(outgoing_cfg) {
-            Ok(o) => process_request(client, o, r).await,
-            Err(e) => abort(client, OutgoingError::GeneralFailure(e), r).await,
-        },
+pub async fn handle_client(
+    mut client: impl IncomingClient + Send,
+    ctx: Arc<ClientContext>,
+    incoming: Arc<IncomingContext>,
+) {
+    let request = async {
+        match ctx.handshake_deadline {
+            Some(deadline) => tokio::time::timeout(deadline, client.next_request())
+                .await
+                .unwrap_or(Err(Error::HandshakeTimeout)),
+            None => client.next_request().await,
+        }
+    };
+    // Clients still negotiating are just dropped
+    let request = tokio::select! {
+        request = request => request,
+        _ = closed(ctx.closing.clone()) => return,
+    };
+    match request {
+        Ok((cmd, r)) => {
+            ctx.stats.request();
+            let r = match ctx.restore_fake_ip(r.clone()) {
+                Ok(r) => r,
+                Err(e) => {
+                    ctx.stats.failed();
+                    return abort(client, e, r).await;
+                }
+            };
+            if let (Command::Connect, Some(quotas)) = (cmd, &ctx.quotas) {
+                if let Err(e) = quotas.check(client.user()) {
+                    info!("refusing {}: {}", r, e);
+                    ctx.stats.failed();
+                    return abort(client, e, r).await;
+                }
+            }
+            let entry = match &incoming.outgoing {
+                Some(entry) => entry,
+                None => {
+                    let source = client.peer_addr().map(|a| a.ip());
+                    if cmd == Command::Connect {
+                        ctx.router.route(&r, client.user(), source).await
+                    } else {
+                        // A lookup is not worth another one for GeoIP rules
+                        ctx.router.route_no_resolve(&r, client.user(), source)
+                    }
+                }
+            };
+            let from = client
+                .peer_addr()
+                .map_or("local client".to_string(), |a| a.to_string());
+            info!(
+                "{} -> {} via outgoing {} (incoming {})",
+                from, r, entry.conf.name, incoming.name
+            );
+            match get_outgoing(entry.clone()) {
+                Ok(o) if cmd == Command::Connect => {
+                    process_request(client, o, r, &ctx, &incoming, &entry.conf.name).await
+                }
+                Ok(o) => resolve_request(client, o, cmd, r).await,
+                Err(e) => {
+                    ctx.stats.failed();
+                    abort(client, OutgoingError::GeneralFailure(e), r).await
+                }
+            }
+        }
+        Err(e @ Error::HandshakeTimeout) => {
+            ctx.stats.handshake_timeout();
+            info!(
+                "closing {}: {}",
+                client
+                    .peer_addr()
+                    .map_or("local client".to_string(), |a| a.to_string()),
+                e
+            );
+        }
         Err(e) => {
             error!("can't handle request: {}", e)
         }
     }
 }
+
+#[cfg(test)]
+mod test_client_manager {
+    use super::*;
+    use crate::config::{OutgoingConfig, QuotaConfig, QuotaPeriod};
+    use crate::connection::Direction;
+
+    fn router() -> Router {
+        let direct = toml::from_str::<OutgoingConfig>("type = \"Direct\"").unwrap();
+        Router::from_cfg(
+            vec![direct],
+            &[],
+            None,
+            Arc::new(Resolver::default()),
+            Arc::new(Stats::default()),
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn meter_ends_connections_over_quota() {
+        let path = std::env::temp_dir().join(format!("rocks-meter-{}.toml", std::process::id()));
+        let quotas = Arc::new(
+            Quotas::from_cfg(QuotaConfig {
+                state_file: path.to_str().unwrap().to_string(),
+                period: QuotaPeriod::Daily,
+                per_user: Some(100),
+                users: Default::default(),
+                save_interval: 60,
+            })
+            .unwrap(),
+        );
+        let ctx = ClientContext {
+            quotas: Some(quotas.clone()),
+            ..ClientContext::for_test(router())
+        };
+        ctx.stats.subscribe(quotas.clone());
+        let every = Duration::from_millis(10);
+
+        let progress = Progress::new();
+        progress.transferred(Direction::Up, 60);
+        let anonymous = meter(&ctx, None, &progress, every);
+        // Clients without a user have no quota
+        assert!(tokio::time::timeout(Duration::from_millis(50), anonymous)
+            .await
+            .is_err());
+
+        let progress = Progress::new();
+        progress.transferred(Direction::Down, 60);
+        let alice = meter(&ctx, Some("alice"), &progress, every);
+        tokio::pin!(alice);
+        assert!(tokio::time::timeout(Duration::from_millis(50), &mut alice)
+            .await
+            .is_err());
+        assert!(quotas.check(Some("alice")).is_ok());
+        progress.transferred(Direction::Up, 40);
+        assert_eq!(alice.await, CloseReason::QuotaExceeded);
+        assert!(quotas.check(Some("alice")).is_err());
+        assert_eq!(progress.unreported(), 0);
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -1,7 +1,10 @@
+use serde::{Deserialize as _, Deserializer};
 use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::net::{SocketAddr, ToSocketAddrs};
 
 use crate::error::Error;
+use crate::req_addr::ReqAddr;
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct CfgAddr {
     pub ip: Option<String>,
@@ -30,6 +33,41 @@ impl CfgAddr {
             _ => Err(Error::from_description("invalid address in config")),
         }
     }
+    /// Like `into_addr`, but leaves domains for whoever connects to resolve
+    pub fn into_req_addr(self) -> Result<ReqAddr, Error> {
+        match self {
+            CfgAddr {
+                ip: Some(ip),
+                domain: _,
+                port,
+            } => Ok(ReqAddr::from_addr(SocketAddr::new(ip.parse()?, port))),
+            CfgAddr {
+                ip: None,
+                domain: Some(domain),
+                port,
+            } => Ok(ReqAddr::Domain(domain, port)),
+            _ => Err(Error::from_description("invalid address in config")),
+        }
+    }
+}
+
+/// Accepts either a single table (`[outgoing]`) or an array of tables
+/// (`[[outgoing]]`), so older single-item configs keep working.
+fn one_or_many<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: serde::de::DeserializeOwned,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany<T> {
+        One(T),
+        Many(Vec<T>),
+    }
+    Ok(match OneOrMany::<T>::deserialize(deserializer)? {
+        OneOrMany::One(t) => vec![t],
+        OneOrMany::Many(v) => v,
+    })
 }
 
 #[derive(Deserialize, Serialize)]
@@ -40,15 +78,98 @@ pub enum IncomingType {
     Redirect,
 }
 
+fn default_incoming_name() -> String {
+    "default".to_string()
+}
+
 #[derive(Deserialize, Serialize)]
 pub struct IncomingConfig {
+    #[serde(default = "default_incoming_name")]
+    pub name: String,
     pub r#type: IncomingType,
     pub userfile: Option<String>,
-    pub listen_addr: CfgAddr,
+    /// One address or a list; `::` with `socket.only_v6 = false` accepts
+    /// both IPv4 and IPv6.
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub listen_addr: Vec<CfgAddr>,
+    #[serde(default)]
+    pub listen_unix: Vec<UnixListenConfig>,
     pub ssl: Option<SslConfig>,
+    /// Sends every request to this outgoing instead of following the rules
+    pub outgoing: Option<String>,
+    pub proxy_protocol: Option<ProxyProtocolConfig>,
+    #[serde(default)]
+    pub socket: SocketConfig,
+    /// Shared by all clients of the incoming
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+}
+
+/// PROXY protocol headers in front of client connections, as sent by load
+/// balancers. Connections from `trusted` sources must start with one (v1 or
+/// v2); other sources are taken as they are. Unix socket clients count as
+/// trusted.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ProxyProtocolConfig {
+    pub trusted: Vec<String>,
+}
+
+/// A Unix domain socket an incoming listens on
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct UnixListenConfig {
+    pub path: String,
+    /// Permission bits in octal, e.g. `"660"`
+    pub mode: Option<String>,
+    /// User and group of the socket file, by name or id
+    pub owner: Option<String>,
+    pub group: Option<String>,
+    /// When not empty, only peers running as one of these users or groups
+    /// (from `SO_PEERCRED`) are served.
+    #[serde(default)]
+    pub allow_uid: Vec<u32>,
+    #[serde(default)]
+    pub allow_gid: Vec<u32>,
 }
 
-#[derive(Deserialize, Serialize, Clone)]
+/// TCP options for the sockets of an incoming, an outgoing or the DNS
+/// server. Anything left out keeps the OS default.
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct SocketConfig {
+    pub nodelay: Option<bool>,
+    /// Seconds of idleness before keepalive probes start; setting any of
+    /// the keepalive values turns keepalive on.
+    pub keepalive_idle: Option<u64>,
+    /// Seconds between keepalive probes
+    pub keepalive_interval: Option<u64>,
+    /// Unanswered probes before the connection is dropped
+    pub keepalive_count: Option<u32>,
+    pub send_buffer: Option<usize>,
+    pub recv_buffer: Option<usize>,
+    /// Only applies to IPv6 listeners
+    pub only_v6: Option<bool>,
+    /// TCP Fast Open (Linux only)
+    pub fast_open: bool,
+}
+
+/// Reads a userfile with one `user:password` pair per line. Empty lines and
+/// lines starting with `#` are skipped.
+pub fn read_userfile(path: &str) -> Result<HashMap<String, String>, Error> {
+    let content = std::fs::read_to_string(path)?;
+    let mut users = HashMap::new();
+    for line in content.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (user, pass) = line
+            .split_once(':')
+            .ok_or_else(|| Error::from_description(&format!("invalid line in {}", path)))?;
+        users.insert(user.to_string(), pass.to_string());
+    }
+    Ok(users)
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
 pub enum OutgoingType {
     Rocks,
     Direct,
@@ -61,11 +182,358 @@ pub struct SslConfig {
     pub certfile: String,
 }
 
-#[derive(Deserialize, Serialize, Clone)]
+fn default_outgoing_name() -> String {
+    "default".into()
+}
+
+/// What an `Ignore` outgoing does with the requests routed to it
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default)]
+pub enum IgnoreReply {
+    /// Reject with SOCKS5 reply 2
+    #[default]
+    ConnectionNotAllowed,
+    /// Reject with SOCKS5 reply 4
+    HostUnreachable,
+    /// Accept the connection, then throw away whatever the client sends
+    Discard,
+}
+
+/// PROXY protocol header version sent ahead of the client's data
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProxyProtocolVersion {
+    V1,
+    V2,
+}
+
+/// Destination access control of a `Direct` outgoing. An address that
+/// matches `allow_cidr` is always allowed, otherwise it is denied if it
+/// matches `deny_cidr` or, with `block_private`, is a loopback, private,
+/// link-local or multicast address. The same goes for domains (by suffix);
+/// ports must be in `allow_port` when it is given and not in `deny_port`.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default)]
+pub struct AclConfig {
+    pub allow_cidr: Vec<String>,
+    pub deny_cidr: Vec<String>,
+    pub allow_port: Vec<String>,
+    pub deny_port: Vec<String>,
+    pub allow_domain: Vec<String>,
+    pub deny_domain: Vec<String>,
+    /// On unless turned off, for outgoings meant to reach the LAN
+    pub block_private: bool,
+}
+
+impl Default for AclConfig {
+    fn default() -> Self {
+        AclConfig {
+            allow_cidr: vec![],
+            deny_cidr: vec![],
+            allow_port: vec![],
+            deny_port: vec![],
+            allow_domain: vec![],
+            deny_domain: vec![],
+            block_private: true,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct OutgoingConfig {
+    #[serde(default = "default_outgoing_name")]
+    pub name: String,
     pub r#type: OutgoingType,
     pub user: Option<String>,
     pub listen_addr: Option<CfgAddr>,
+    /// Only used by `Ignore` outgoings
+    #[serde(default)]
+    pub reply: IgnoreReply,
+    /// Only used by `Direct` outgoings
+    #[serde(default)]
+    pub acl: AclConfig,
+    /// Source addresses for `Direct` connections, used in turn
+    #[serde(default)]
+    pub bind_addr: Vec<String>,
+    /// `SO_BINDTODEVICE` for `Direct` connections (Linux only)
+    pub bind_interface: Option<String>,
+    /// `SO_MARK` for `Direct` connections, for policy routing (Linux only)
+    pub fwmark: Option<u32>,
+    /// Only used by `Direct` outgoings
+    #[serde(default)]
+    pub socket: SocketConfig,
+    /// Tells the destination of `Direct` connections who the client is
+    pub proxy_protocol: Option<ProxyProtocolVersion>,
+}
+
+/// One entry of the ordered `[[rule]]` table. Every condition that is given
+/// must match (any of its listed values); a rule without conditions matches
+/// everything.
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct RuleConfig {
+    pub domain_suffix: Vec<String>,
+    pub domain_regex: Vec<String>,
+    pub ip_cidr: Vec<String>,
+    /// Single ports (`"443"`) or inclusive ranges (`"8000-8100"`)
+    pub port: Vec<String>,
+    pub user: Vec<String>,
+    /// Client addresses, after any PROXY protocol header
+    pub source_cidr: Vec<String>,
+    /// ISO country codes, looked up in `geoip.country_db`
+    pub geoip_country: Vec<String>,
+    /// AS numbers, looked up in `geoip.asn_db`
+    pub geoip_asn: Vec<u32>,
+    pub outgoing: String,
+}
+
+/// Static DNS overrides. `entries` maps a domain, or `*.domain` for all its
+/// subdomains, to an IP address or to another domain (CNAME style). `file`
+/// is read in `/etc/hosts` format; `entries` take precedence over it.
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct HostsConfig {
+    pub file: Option<String>,
+    pub entries: HashMap<String, String>,
+}
+
+fn default_dns_cache_size() -> usize {
+    1024
+}
+
+fn default_fake_ip_ttl() -> u64 {
+    600
+}
+
+/// Local DNS server. Queries are forwarded over TCP to `upstream`, through
+/// the outgoing that the queried name is routed to.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct DnsConfig {
+    pub listen_addr: CfgAddr,
+    pub upstream: CfgAddr,
+    /// Maximum number of cached answers, 0 disables the cache
+    #[serde(default = "default_dns_cache_size")]
+    pub cache_size: usize,
+    /// When set, A queries are answered with addresses from this range (for
+    /// example `198.18.0.0/15`), which connections are mapped back from.
+    pub fake_ip_range: Option<String>,
+    /// Seconds a fake address stays mapped after it was last used
+    #[serde(default = "default_fake_ip_ttl")]
+    pub fake_ip_ttl: u64,
+    /// Options of the TCP listener
+    #[serde(default)]
+    pub socket: SocketConfig,
+}
+
+fn default_geoip_reload_interval() -> u64 {
+    60
+}
+
+/// Local MaxMind databases used by `geoip_*` rule conditions. The files are
+/// checked for changes every `reload_interval` seconds and reloaded in place.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct GeoIpConfig {
+    pub country_db: Option<String>,
+    pub asn_db: Option<String>,
+    #[serde(default = "default_geoip_reload_interval")]
+    pub reload_interval: u64,
+}
+
+fn default_handshake_timeout() -> u64 {
+    30
+}
+
+fn default_idle_timeout() -> u64 {
+    300
+}
+
+fn default_handshake_deadline() -> u64 {
+    60
+}
+
+fn default_drain_timeout() -> u64 {
+    30
+}
+
+/// Seconds a connection may stay silent before it is dropped; 0 waits
+/// forever.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct TimeoutConfig {
+    /// Between the reads and writes of the SOCKS handshake
+    #[serde(default = "default_handshake_timeout")]
+    pub handshake: u64,
+    /// For the whole handshake, from accepting the client to its request
+    #[serde(default = "default_handshake_deadline")]
+    pub handshake_deadline: u64,
+    /// Once connected, with no data going either way
+    #[serde(default = "default_idle_timeout")]
+    pub idle: u64,
+    /// After SIGTERM or SIGINT, for the connections still open to finish
+    /// before they are closed
+    #[serde(default = "default_drain_timeout")]
+    pub drain: u64,
+}
+
+impl Default for TimeoutConfig {
+    fn default() -> Self {
+        TimeoutConfig {
+            handshake: default_handshake_timeout(),
+            handshake_deadline: default_handshake_deadline(),
+            idle: default_idle_timeout(),
+            drain: default_drain_timeout(),
+        }
+    }
+}
+
+impl TimeoutConfig {
+    fn duration(secs: u64) -> Option<std::time::Duration> {
+        (secs > 0).then(|| std::time::Duration::from_secs(secs))
+    }
+    pub fn handshake(&self) -> Option<std::time::Duration> {
+        Self::duration(self.handshake)
+    }
+    pub fn handshake_deadline(&self) -> Option<std::time::Duration> {
+        Self::duration(self.handshake_deadline)
+    }
+    pub fn idle(&self) -> Option<std::time::Duration> {
+        Self::duration(self.idle)
+    }
+    pub fn drain(&self) -> Option<std::time::Duration> {
+        Self::duration(self.drain)
+    }
+}
+
+/// Bytes per second; a direction without a limit (or with 0) is not
+/// limited.
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    /// From clients to their targets
+    pub upload: Option<u64>,
+    /// From targets to their clients
+    pub download: Option<u64>,
+}
+
+/// The `[rate_limit]` section. `upload` and `download` limit the whole
+/// server; each authenticated user gets buckets of their own, shared by all
+/// of their connections.
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct RateLimitsConfig {
+    pub upload: Option<u64>,
+    pub download: Option<u64>,
+    /// For every user not listed in `users`
+    pub per_user: RateLimitConfig,
+    pub users: HashMap<String, RateLimitConfig>,
+}
+
+impl RateLimitsConfig {
+    pub fn global(&self) -> RateLimitConfig {
+        RateLimitConfig {
+            upload: self.upload,
+            download: self.download,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QuotaPeriod {
+    Daily,
+    Monthly,
+}
+
+fn default_quota_period() -> QuotaPeriod {
+    QuotaPeriod::Monthly
+}
+
+fn default_quota_save_interval() -> u64 {
+    60
+}
+
+/// Bytes (up and down together) each authenticated user may transfer per
+/// calendar day or month, in UTC. Usage is counted while connections relay
+/// data; a connection is closed once its user's quota is used up.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct QuotaConfig {
+    pub state_file: String,
+    #[serde(default = "default_quota_period")]
+    pub period: QuotaPeriod,
+    /// For every user not listed in `users`; unlimited when unset
+    pub per_user: Option<u64>,
+    #[serde(default)]
+    pub users: HashMap<String, u64>,
+    /// Seconds between writes of the state file
+    #[serde(default = "default_quota_save_interval")]
+    pub save_interval: u64,
+}
+
+impl QuotaConfig {
+    pub fn limit(&self, user: &str) -> Option<u64> {
+        self.users.get(user).copied().or(self.per_user)
+    }
+}
+
+fn default_buffer_size() -> usize {
+    16 * 1024
+}
+
+fn default_buffer_memory() -> usize {
+    64 * 1024 * 1024
+}
+
+/// Buffers for relaying, taken from one pool. Connections relayed with
+/// splice(2) need none.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct BufferConfig {
+    /// Bytes per buffer; each connection uses two
+    #[serde(default = "default_buffer_size")]
+    pub size: usize,
+    /// Bytes all buffers together may take up; beyond that new connections
+    /// wait a while for buffers to be returned, and are turned down if none
+    /// are
+    #[serde(default = "default_buffer_memory")]
+    pub max_memory: usize,
+}
+
+impl Default for BufferConfig {
+    fn default() -> Self {
+        BufferConfig {
+            size: default_buffer_size(),
+            max_memory: default_buffer_memory(),
+        }
+    }
+}
+
+/// The `[connections]` section: limits on accepted clients, over all
+/// incomings. Clients are counted by the address they connect from (that
+/// of a load balancer, when they come through one).
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct ConnectionLimitConfig {
+    /// Concurrent connections
+    pub max: Option<usize>,
+    /// Concurrent connections from one IP address
+    pub max_per_ip: Option<usize>,
+    /// New connections per second, after a burst of as many
+    pub new_per_second: Option<u32>,
+}
+
+fn default_summary_interval() -> u64 {
+    600
+}
+
+/// Connection statistics
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct StatsConfig {
+    /// Seconds between summaries in the log, 0 disables them
+    #[serde(default = "default_summary_interval")]
+    pub summary_interval: u64,
+}
+
+impl Default for StatsConfig {
+    fn default() -> Self {
+        StatsConfig {
+            summary_interval: default_summary_interval(),
+        }
+    }
 }
 
 // #[derive(Deserialize, Serialize)]
@@ -74,8 +542,26 @@ pub struct OutgoingConfig {
 // }
 #[derive(Deserialize, Serialize)]
 pub struct RocksConfig {
-    pub incoming: IncomingConfig,
-    pub outgoing: OutgoingConfig,
+    #[serde(deserialize_with = "one_or_many")]
+    pub incoming: Vec<IncomingConfig>,
+    #[serde(deserialize_with = "one_or_many")]
+    pub outgoing: Vec<OutgoingConfig>,
+    #[serde(default, rename = "rule")]
+    pub rules: Vec<RuleConfig>,
+    pub geoip: Option<GeoIpConfig>,
+    pub hosts: Option<HostsConfig>,
+    pub dns: Option<DnsConfig>,
+    #[serde(default)]
+    pub timeouts: TimeoutConfig,
+    #[serde(default)]
+    pub stats: StatsConfig,
+    #[serde(default)]
+    pub rate_limit: RateLimitsConfig,
+    pub quota: Option<QuotaConfig>,
+    #[serde(default)]
+    pub buffers: BufferConfig,
+    #[serde(default)]
+    pub connections: ConnectionLimitConfig,
 }
 
 // // #[allow(dead_code)]
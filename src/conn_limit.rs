@@ -0,0 +1,192 @@
+//! Admission of new clients: caps on concurrent connections, overall and
+//! per source address, and on the rate of new connections.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::config::ConnectionLimitConfig;
+
+#[derive(Debug)]
+pub struct ConnLimiter {
+    conf: ConnectionLimitConfig,
+    active: AtomicUsize,
+    per_ip: Mutex<HashMap<IpAddr, usize>>,
+    /// Tokens for new connections and when they were counted
+    rate: Mutex<(f64, Instant)>,
+}
+
+/// Counts a client as connected until dropped
+#[derive(Debug)]
+pub struct ConnPermit {
+    limiter: Arc<ConnLimiter>,
+    ip: Option<IpAddr>,
+}
+
+impl ConnLimiter {
+    pub fn from_cfg(conf: ConnectionLimitConfig) -> Self {
+        let burst = conf.new_per_second.unwrap_or(0) as f64;
+        ConnLimiter {
+            conf,
+            active: AtomicUsize::new(0),
+            per_ip: Mutex::new(HashMap::new()),
+            rate: Mutex::new((burst, Instant::now())),
+        }
+    }
+
+    /// Clients admitted and not yet gone
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// Lets a client from `ip` in, or says why not. Clients without an IP
+    /// address only count against the overall limits. A token for a new
+    /// connection is only used once the caps let the client in.
+    pub fn admit(self: &Arc<Self>, ip: Option<IpAddr>) -> Result<ConnPermit, &'static str> {
+        let max = self.conf.max.unwrap_or(usize::MAX);
+        self.active
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+                (n < max).then_some(n + 1)
+            })
+            .map_err(|_| "too many connections")?;
+        // Gives the slot back if the client is turned away below
+        let mut permit = ConnPermit {
+            limiter: self.clone(),
+            ip: None,
+        };
+        if let (Some(max), Some(ip)) = (self.conf.max_per_ip, ip) {
+            let mut per_ip = self.per_ip.lock().unwrap();
+            let count = per_ip.entry(ip).or_insert(0);
+            if *count >= max {
+                return Err("too many connections from this address");
+            }
+            *count += 1;
+            permit.ip = Some(ip);
+        }
+        if let Some(rate) = self.conf.new_per_second {
+            let rate = rate as f64;
+            let mut state = self.rate.lock().unwrap();
+            let (tokens, counted) = &mut *state;
+            let now = Instant::now();
+            *tokens = (*tokens + now.duration_since(*counted).as_secs_f64() * rate).min(rate);
+            *counted = now;
+            if *tokens < 1.0 {
+                return Err("too many new connections");
+            }
+            *tokens -= 1.0;
+        }
+        Ok(permit)
+    }
+}
+
+impl Drop for ConnPermit {
+    fn drop(&mut self) {
+        self.limiter.active.fetch_sub(1, Ordering::Relaxed);
+        if let Some(ip) = self.ip {
+            let mut per_ip = self.limiter.per_ip.lock().unwrap();
+            if let Some(count) = per_ip.get_mut(&ip) {
+                *count -= 1;
+                if *count == 0 {
+                    per_ip.remove(&ip);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_conn_limit {
+    use super::*;
+
+    fn ip(s: &str) -> Option<IpAddr> {
+        Some(s.parse().unwrap())
+    }
+
+    #[test]
+    fn caps() {
+        let limiter = Arc::new(ConnLimiter::from_cfg(ConnectionLimitConfig {
+            max: Some(3),
+            max_per_ip: Some(2),
+            new_per_second: None,
+        }));
+        let a1 = limiter.admit(ip("192.0.2.1")).unwrap();
+        let _a2 = limiter.admit(ip("192.0.2.1")).unwrap();
+        assert!(limiter.admit(ip("192.0.2.1")).is_err());
+        let _b = limiter.admit(None).unwrap();
+        assert!(limiter.admit(ip("192.0.2.2")).is_err());
+        drop(a1);
+        let _a3 = limiter.admit(ip("192.0.2.1")).unwrap();
+        assert!(limiter.admit(ip("192.0.2.1")).is_err());
+    }
+
+    #[test]
+    fn new_connection_rate() {
+        let limiter = Arc::new(ConnLimiter::from_cfg(ConnectionLimitConfig {
+            new_per_second: Some(2),
+            ..Default::default()
+        }));
+        assert!(limiter.admit(None).is_ok());
+        assert!(limiter.admit(None).is_ok());
+        assert!(limiter.admit(None).is_err());
+        std::thread::sleep(std::time::Duration::from_millis(600));
+        assert!(limiter.admit(None).is_ok());
+    }
+
+    #[test]
+    fn rejected_clients_keep_tokens() {
+        let limiter = Arc::new(ConnLimiter::from_cfg(ConnectionLimitConfig {
+            max_per_ip: Some(1),
+            new_per_second: Some(2),
+            ..Default::default()
+        }));
+        let _a = limiter.admit(ip("192.0.2.1")).unwrap();
+        for _ in 0..5 {
+            assert_eq!(
+                limiter.admit(ip("192.0.2.1")).unwrap_err(),
+                "too many connections from this address"
+            );
+        }
+        assert!(limiter.admit(ip("192.0.2.2")).is_ok());
+        // Turned away by the rate, and nothing stays counted
+        assert_eq!(
+            limiter.admit(ip("192.0.2.3")).unwrap_err(),
+            "too many new connections"
+        );
+        assert_eq!(limiter.active(), 1);
+        assert!(limiter
+            .per_ip
+            .lock()
+            .unwrap()
+            .get(&"192.0.2.3".parse().unwrap())
+            .is_none());
+    }
+
+    #[test]
+    fn concurrent_admissions_stay_under_max() {
+        let limiter = Arc::new(ConnLimiter::from_cfg(ConnectionLimitConfig {
+            max: Some(4),
+            ..Default::default()
+        }));
+        let threads = (0..16)
+            .map(|_| {
+                let limiter = limiter.clone();
+                std::thread::spawn(move || {
+                    let mut admitted = 0;
+                    for _ in 0..1000 {
+                        if let Ok(permit) = limiter.admit(None) {
+                            assert!(limiter.active() <= 4);
+                            admitted += 1;
+                            drop(permit);
+                        }
+                    }
+                    admitted
+                })
+            })
+            .collect::<Vec<_>>();
+        let admitted: usize = threads.into_iter().map(|t| t.join().unwrap()).sum();
+        assert!(admitted > 0);
+        assert_eq!(limiter.active(), 0);
+    }
+}
@@ -1,8 +1,17 @@
+use crate::bufpool::{Buffers, PooledBuf};
 use crate::error::Error;
+use crate::ratelimit::{Limits, TokenBucket};
 use crate::req_addr::ReqAddr;
-use log::info;
+#[cfg(target_os = "linux")]
+use crate::splice::{self, Pipe};
+use crate::stats::{CloseReason, Transfer};
+use futures::Future;
+use log::{debug, info};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::{
-    io::{AsyncRead, AsyncWrite},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
     net::TcpStream,
 };
 
@@ -12,44 +21,365 @@ pub trait Connection: AsyncRead + AsyncWrite + Send + Unpin {
     fn l_addr(&self) -> Result<ReqAddr, Error>;
     fn p_addr(&self) -> Result<ReqAddr, Error>;
     fn split(self) -> (Self::ReadHalf, Self::WriteHalf);
+    /// The TCP socket underneath, if this is a plain TCP connection
+    fn as_tcp(&self) -> Option<&TcpStream> {
+        None
+    }
 }
 
 impl Connection for TcpStream {
     type ReadHalf = tokio::io::ReadHalf<TcpStream>;
     type WriteHalf = tokio::io::WriteHalf<TcpStream>;
     fn l_addr(&self) -> Result<ReqAddr, Error> {
-        Ok(self.local_addr().map(|addr| ReqAddr::from_addr(addr))?)
+        Ok(self.local_addr().map(ReqAddr::from_addr)?)
     }
     fn p_addr(&self) -> Result<ReqAddr, Error> {
-        Ok(self.peer_addr().map(|addr| ReqAddr::from_addr(addr))?)
+        Ok(self.peer_addr().map(ReqAddr::from_addr)?)
     }
     fn split(self) -> (Self::ReadHalf, Self::WriteHalf) {
         tokio::io::split(self)
     }
+    fn as_tcp(&self) -> Option<&TcpStream> {
+        Some(self)
+    }
+}
+
+/// What has been relayed so far. Times are offsets in milliseconds from
+/// `start`.
+pub struct Progress {
+    start: Instant,
+    last: AtomicU64,
+    /// Offset of the first byte down plus one, 0 before it
+    first_down: AtomicU64,
+    up: AtomicU64,
+    down: AtomicU64,
+    /// Bytes (both ways) already handed out by `unreported`
+    reported: AtomicU64,
+}
+
+#[derive(Clone, Copy)]
+pub enum Direction {
+    Up,
+    Down,
+}
+
+impl Progress {
+    pub fn new() -> Self {
+        Progress {
+            start: Instant::now(),
+            last: AtomicU64::new(0),
+            first_down: AtomicU64::new(0),
+            up: AtomicU64::new(0),
+            down: AtomicU64::new(0),
+            reported: AtomicU64::new(0),
+        }
+    }
+    pub fn transferred(&self, dir: Direction, n: usize) {
+        let now = self.start.elapsed().as_millis() as u64;
+        self.last.store(now, Ordering::Relaxed);
+        match dir {
+            Direction::Up => self.up.fetch_add(n as u64, Ordering::Relaxed),
+            Direction::Down => {
+                let _ = self.first_down.compare_exchange(
+                    0,
+                    now + 1,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                );
+                self.down.fetch_add(n as u64, Ordering::Relaxed)
+            }
+        };
+    }
+    fn at(&self, offset: u64) -> Instant {
+        self.start + Duration::from_millis(offset)
+    }
+    fn last(&self) -> Instant {
+        self.at(self.last.load(Ordering::Relaxed))
+    }
+    /// Bytes relayed (both ways) since the last call
+    pub fn unreported(&self) -> u64 {
+        let total = self.up.load(Ordering::Relaxed) + self.down.load(Ordering::Relaxed);
+        total - self.reported.swap(total, Ordering::Relaxed)
+    }
+    pub fn finish(self, close: CloseReason) -> Transfer {
+        let first_down = self.first_down.load(Ordering::Relaxed);
+        Transfer {
+            up: self.up.load(Ordering::Relaxed),
+            down: self.down.load(Ordering::Relaxed),
+            first_down: (first_down > 0).then(|| self.at(first_down - 1)),
+            close,
+        }
+    }
+}
+
+/// Copies until `reader` hits EOF, then shuts down `writer` so the other
+/// end sees the half-close.
+async fn copy_half(
+    mut reader: impl AsyncRead + Unpin,
+    mut writer: impl AsyncWrite + Unpin,
+    mut buf: PooledBuf,
+    progress: &Progress,
+    dir: Direction,
+    buckets: &[Arc<TokenBucket>],
+) -> std::io::Result<()> {
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            // The peer may already be gone, which is no reason to fail
+            if let Err(e) = writer.shutdown().await {
+                debug!("shutdown after EOF: {}", e);
+            }
+            return Ok(());
+        }
+        for bucket in buckets {
+            bucket.take(n).await;
+        }
+        writer.write_all(&buf[..n]).await?;
+        progress.transferred(dir, n);
+    }
 }
 
-pub async fn bicopy(incoming: impl Connection, outgoing: impl Connection) -> Result<(), Error> {
-    let riport = incoming.p_addr()?.port();
-    let loport = outgoing.l_addr()?.port();
+/// Resolves once nothing was transferred for `idle`
+async fn idle_watchdog(progress: &Progress, idle: Option<Duration>) {
+    let idle = match idle {
+        Some(idle) => idle,
+        None => return futures::future::pending().await,
+    };
+    loop {
+        let deadline = progress.last() + idle;
+        if Instant::now() >= deadline {
+            return;
+        }
+        tokio::time::sleep_until(deadline.into()).await;
+    }
+}
+
+/// Runs both directions until they are done, either fails, the connection
+/// is idle for too long, or `stop` says why it has to end
+async fn relay(
+    i2o: impl Future<Output = std::io::Result<()>>,
+    o2i: impl Future<Output = std::io::Result<()>>,
+    progress: &Progress,
+    idle: Option<Duration>,
+    stop: impl Future<Output = CloseReason>,
+) -> CloseReason {
+    let i2o = async {
+        i2o.await
+            .map_err(|e| format!("copy error first half: {}", e))
+    };
+    let o2i = async {
+        o2i.await
+            .map_err(|e| format!("copy error second half: {}", e))
+    };
+    tokio::select! {
+        res = futures::future::try_join(i2o, o2i) => match res {
+            Ok(_) => CloseReason::Done,
+            Err(e) => CloseReason::Error(e),
+        },
+        _ = idle_watchdog(progress, idle) => CloseReason::IdleTimeout,
+        close = stop => close,
+    }
+}
 
-    let roport = incoming.l_addr()?.port();
-    let liport = outgoing.p_addr()?.port();
+/// Relays between the two connections until both directions reached EOF,
+/// either one failed, no data went either way for `idle`, or `stop`
+/// resolves. Data is let through as fast as the buckets in `limits` allow,
+/// and counted in `progress` as it goes.
+///
+/// On Linux two plain TCP connections are relayed with splice(2), otherwise
+/// through a pair of buffers from `buffers`.
+pub async fn bicopy(
+    incoming: impl Connection,
+    outgoing: impl Connection,
+    idle: Option<Duration>,
+    limits: &Limits,
+    buffers: Buffers<'_>,
+    progress: &Progress,
+    stop: impl Future<Output = CloseReason>,
+) -> CloseReason {
+    // Unix socket clients have no ports
+    let port =
+        |addr: Result<ReqAddr, Error>| addr.map_or("-".to_string(), |a| a.port().to_string());
+    let riport = port(incoming.p_addr());
+    let loport = port(outgoing.l_addr());
+
+    let roport = port(incoming.l_addr());
+    let liport = port(outgoing.p_addr());
     info!("({} -> {} | {} -> {})", riport, roport, loport, liport);
 
-    let (mut rin, mut win) = incoming.split();
-    let (mut rout, mut wout) = outgoing.split();
-
-    let i2o = tokio::io::copy(&mut rin, &mut wout);
-    let o2i = tokio::io::copy(&mut rout, &mut win);
-    match futures::join!(i2o, o2i) {
-        (Ok(_), Ok(_)) => Ok(()),
-        (Err(e), Ok(_)) => Err(Error::from_description(&format!(
-            "copy error first half: {}",
-            e
-        ))),
-        (_, Err(e)) => Err(Error::from_description(&format!(
-            "copy error second half: {}",
-            e
-        ))),
+    #[cfg(target_os = "linux")]
+    if let (Some(tin), Some(tout)) = (incoming.as_tcp(), outgoing.as_tcp()) {
+        match (Pipe::new(), Pipe::new()) {
+            (Ok(up), Ok(down)) => {
+                debug!("relaying with splice");
+                // Dropping the connections on return closes them
+                return relay(
+                    splice::copy_half(tin, tout, up, progress, Direction::Up, &limits.up),
+                    splice::copy_half(tout, tin, down, progress, Direction::Down, &limits.down),
+                    progress,
+                    idle,
+                    stop,
+                )
+                .await;
+            }
+            (Err(e), _) | (_, Err(e)) => debug!("no pipe for splice, copying instead: {}", e),
+        }
+    }
+
+    // The client was told it is connected already; all that is left is to
+    // close it and say why
+    let (up, down) = match buffers.pair().await {
+        Ok(pair) => pair,
+        Err(e) => return CloseReason::Error(e.to_string()),
+    };
+    let (rin, win) = incoming.split();
+    let (rout, wout) = outgoing.split();
+    // Dropping the halves on return closes both connections
+    relay(
+        copy_half(rin, wout, up, progress, Direction::Up, &limits.up),
+        copy_half(rout, win, down, progress, Direction::Down, &limits.down),
+        progress,
+        idle,
+        stop,
+    )
+    .await
+}
+
+#[cfg(test)]
+mod test_connection {
+    use super::*;
+    use crate::bufpool::BufferPool;
+    use tokio::net::TcpListener;
+
+    /// Both ends of a fresh TCP connection
+    async fn pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (client, server)
+    }
+
+    fn buffers() -> Arc<BufferPool> {
+        Arc::new(BufferPool::from_cfg(&Default::default()).unwrap())
+    }
+
+    /// Relays with nothing to stop it early
+    async fn relay_all(
+        incoming: impl Connection,
+        outgoing: impl Connection,
+        idle: Option<Duration>,
+        buffers: &Arc<BufferPool>,
+    ) -> Transfer {
+        let progress = Progress::new();
+        let stop = futures::future::pending();
+        let close = bicopy(
+            incoming,
+            outgoing,
+            idle,
+            &Limits::default(),
+            Buffers::Pool(buffers),
+            &progress,
+            stop,
+        )
+        .await;
+        progress.finish(close)
+    }
+
+    #[tokio::test]
+    async fn relays_half_close_and_counts() {
+        let (mut client, incoming) = pair().await;
+        let (outgoing, mut target) = pair().await;
+        let relay =
+            tokio::spawn(async move { relay_all(incoming, outgoing, None, &buffers()).await });
+        exchange(&mut client, &mut target).await;
+        let transfer = relay.await.unwrap();
+        assert_eq!((transfer.up, transfer.down), (4, 5));
+        assert!(transfer.first_down.is_some());
+        assert_eq!(transfer.close, CloseReason::Done);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn relays_through_pooled_buffers() {
+        use crate::listener::IncomingStream;
+
+        // Not TCP on both ends, so no splice
+        let (mut client, incoming) = tokio::net::UnixStream::pair().unwrap();
+        let (outgoing, mut target) = pair().await;
+        let pool = buffers();
+        let relay_pool = pool.clone();
+        let relay = tokio::spawn(async move {
+            let incoming = IncomingStream::Unix(incoming);
+            relay_all(incoming, outgoing, None, &relay_pool).await
+        });
+        exchange(&mut client, &mut target).await;
+        let transfer = relay.await.unwrap();
+        assert_eq!((transfer.up, transfer.down), (4, 5));
+        assert_eq!(transfer.close, CloseReason::Done);
+        assert!(pool.summary().starts_with("buffers in use 0 allocated 2 "));
+    }
+
+    /// The client sends and half-closes, the target answers and closes
+    async fn exchange(client: &mut (impl AsyncRead + AsyncWrite + Unpin), target: &mut TcpStream) {
+        client.write_all(b"ping").await.unwrap();
+        client.shutdown().await.unwrap();
+        // The target sees the client's EOF and can still answer
+        let mut got = vec![];
+        target.read_to_end(&mut got).await.unwrap();
+        assert_eq!(got, b"ping");
+        target.write_all(b"pong!").await.unwrap();
+        target.shutdown().await.unwrap();
+        let mut got = vec![];
+        client.read_to_end(&mut got).await.unwrap();
+        assert_eq!(got, b"pong!");
+    }
+
+    #[tokio::test]
+    async fn idle_timeout() {
+        let (_client, incoming) = pair().await;
+        let (outgoing, _target) = pair().await;
+        let idle = Some(Duration::from_millis(100));
+        let transfer = relay_all(incoming, outgoing, idle, &buffers()).await;
+        assert_eq!(transfer.close, CloseReason::IdleTimeout);
+        assert_eq!((transfer.up, transfer.down), (0, 0));
+        assert!(transfer.first_down.is_none());
+    }
+
+    #[tokio::test]
+    async fn stops_when_told() {
+        let (mut client, incoming) = pair().await;
+        let (outgoing, mut target) = pair().await;
+        let progress = Progress::new();
+        let (limits, buffers) = (Limits::default(), buffers());
+        let relay = bicopy(
+            incoming,
+            outgoing,
+            None,
+            &limits,
+            Buffers::Pool(&buffers),
+            &progress,
+            async {
+                // Once the first bytes went through
+                while progress.unreported() == 0 {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+                CloseReason::QuotaExceeded
+            },
+        );
+        let traffic = async {
+            client.write_all(b"ping").await.unwrap();
+            let mut got = [0; 4];
+            target.read_exact(&mut got).await.unwrap();
+        };
+        let (close, _) = tokio::join!(relay, traffic);
+        assert_eq!(close, CloseReason::QuotaExceeded);
+        assert_eq!(progress.unreported(), 0);
+        // Both connections were closed
+        let mut rest = vec![];
+        assert_eq!(client.read_to_end(&mut rest).await.unwrap(), 0);
+        assert_eq!(target.read_to_end(&mut rest).await.unwrap(), 0);
+        assert_eq!(progress.finish(close).up, 4);
     }
 }
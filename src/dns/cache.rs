@@ -0,0 +1,188 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::dns::message::{rcode, records, set_id, Question, RCODE_NXDOMAIN, TYPE_OPT};
+
+/// Negative answers without a usable TTL are kept this long
+const NEGATIVE_TTL: u32 = 30;
+
+struct Entry {
+    reply: Vec<u8>,
+    ttl_pos: Vec<usize>,
+    stored: Instant,
+    expires: Instant,
+}
+
+/// Upstream replies by question, kept for the smallest TTL they carry
+pub struct DnsCache {
+    entries: Mutex<HashMap<Question, Entry>>,
+    capacity: usize,
+}
+
+impl DnsCache {
+    pub fn new(capacity: usize) -> Self {
+        DnsCache {
+            entries: Mutex::new(HashMap::new()),
+            capacity,
+        }
+    }
+
+    /// A cached reply for `question`, with the query's ID and the TTLs
+    /// counted down by the time it spent in the cache.
+    pub fn get(&self, question: &Question, id: u16) -> Option<Vec<u8>> {
+        self.get_at(question, id, Instant::now())
+    }
+
+    fn get_at(&self, question: &Question, id: u16, now: Instant) -> Option<Vec<u8>> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.get(question)?;
+        if entry.expires <= now {
+            entries.remove(question);
+            return None;
+        }
+        let elapsed = (now - entry.stored).as_secs() as u32;
+        let mut reply = entry.reply.clone();
+        for &pos in &entry.ttl_pos {
+            let ttl =
+                u32::from_be_bytes([reply[pos], reply[pos + 1], reply[pos + 2], reply[pos + 3]]);
+            reply[pos..pos + 4].copy_from_slice(&ttl.saturating_sub(elapsed).to_be_bytes());
+        }
+        set_id(&mut reply, id);
+        Some(reply)
+    }
+
+    pub fn put(&self, question: Question, reply: &[u8]) {
+        self.put_at(question, reply, Instant::now())
+    }
+
+    fn put_at(&self, question: Question, reply: &[u8], now: Instant) {
+        let rcode = rcode(reply);
+        if self.capacity == 0 || (rcode != 0 && rcode != RCODE_NXDOMAIN) {
+            return;
+        }
+        let records = match records(reply) {
+            Ok(r) => r,
+            Err(_) => return,
+        };
+        let records = records
+            .into_iter()
+            .filter(|r| r.rtype != TYPE_OPT)
+            .collect::<Vec<_>>();
+        let ttl = records
+            .iter()
+            .filter_map(|r| r.ttl(reply).ok())
+            .min()
+            .unwrap_or(NEGATIVE_TTL);
+        if ttl == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.capacity {
+            entries.retain(|_, e| e.expires > now);
+        }
+        if entries.len() >= self.capacity {
+            let soonest = entries
+                .iter()
+                .min_by_key(|(_, e)| e.expires)
+                .map(|(q, _)| q.clone());
+            if let Some(q) = soonest {
+                entries.remove(&q);
+            }
+        }
+        entries.insert(
+            question,
+            Entry {
+                reply: reply.to_vec(),
+                ttl_pos: records.iter().map(|r| r.ttl_pos).collect(),
+                stored: now,
+                expires: now + Duration::from_secs(ttl as u64),
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod test_cache {
+    use super::*;
+    use crate::dns::message::test_message::query;
+    use crate::dns::message::{Query, RCODE_SERVFAIL, TYPE_A};
+
+    fn ttls(reply: &[u8]) -> Vec<u32> {
+        records(reply)
+            .unwrap()
+            .iter()
+            .map(|r| r.ttl(reply).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn ttls_count_down() {
+        let cache = DnsCache::new(8);
+        let msg = query(1, "example.com", TYPE_A, None);
+        let q = Query::parse(&msg).unwrap();
+        let reply = q.address_reply(&["192.0.2.1".parse().unwrap()], 300);
+        let t0 = Instant::now();
+        cache.put_at(q.question.clone(), &reply, t0);
+        let cached = cache
+            .get_at(&q.question, 0xbeef, t0 + Duration::from_secs(100))
+            .unwrap();
+        assert_eq!(&cached[..2], &[0xbe, 0xef]);
+        assert_eq!(ttls(&cached), [200]);
+        // Nothing else changes
+        let mut restored = cached.clone();
+        set_id(&mut restored, 1);
+        let ttl_pos = records(&cached).unwrap()[0].ttl_pos;
+        restored[ttl_pos..ttl_pos + 4].copy_from_slice(&300u32.to_be_bytes());
+        assert_eq!(restored, reply);
+        assert!(cache
+            .get_at(&q.question, 1, t0 + Duration::from_secs(300))
+            .is_none());
+        // and the expired entry is gone
+        assert!(cache.get_at(&q.question, 1, t0).is_none());
+    }
+
+    #[test]
+    fn negative_and_failed_replies() {
+        let cache = DnsCache::new(8);
+        let t0 = Instant::now();
+        let msg = query(1, "missing.example", TYPE_A, None);
+        let q = Query::parse(&msg).unwrap();
+        cache.put_at(q.question.clone(), &q.error_reply(RCODE_NXDOMAIN), t0);
+        let later = t0 + Duration::from_secs(NEGATIVE_TTL as u64 - 1);
+        assert_eq!(
+            rcode(&cache.get_at(&q.question, 1, later).unwrap()),
+            RCODE_NXDOMAIN
+        );
+        let expired = t0 + Duration::from_secs(NEGATIVE_TTL as u64);
+        assert!(cache.get_at(&q.question, 1, expired).is_none());
+
+        let msg = query(1, "broken.example", TYPE_A, None);
+        let q = Query::parse(&msg).unwrap();
+        cache.put_at(q.question.clone(), &q.error_reply(RCODE_SERVFAIL), t0);
+        assert!(cache.get_at(&q.question, 1, t0).is_none());
+        // Nor are answers with a zero TTL kept
+        let reply = q.address_reply(&["192.0.2.1".parse().unwrap()], 0);
+        cache.put_at(q.question.clone(), &reply, t0);
+        assert!(cache.get_at(&q.question, 1, t0).is_none());
+    }
+
+    #[test]
+    fn evicts_soonest_expiring() {
+        let cache = DnsCache::new(2);
+        let t0 = Instant::now();
+        let put = |name: &str, ttl| {
+            let msg = query(1, name, TYPE_A, None);
+            let q = Query::parse(&msg).unwrap();
+            let reply = q.address_reply(&["192.0.2.1".parse().unwrap()], ttl);
+            cache.put_at(q.question.clone(), &reply, t0);
+            q.question
+        };
+        let long = put("long.example", 600);
+        let short = put("short.example", 60);
+        let new = put("new.example", 300);
+        assert!(cache.get_at(&long, 1, t0).is_some());
+        assert!(cache.get_at(&short, 1, t0).is_none());
+        assert!(cache.get_at(&new, 1, t0).is_some());
+    }
+}
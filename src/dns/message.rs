@@ -0,0 +1,310 @@
+//! Just enough of the DNS wire format (RFC 1035) to route, cache and answer
+//! queries; everything else is passed through untouched.
+
+use std::net::IpAddr;
+
+use crate::error::Error;
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_AAAA: u16 = 28;
+pub const TYPE_OPT: u16 = 41;
+pub const CLASS_IN: u16 = 1;
+
+pub const RCODE_FORMERR: u8 = 1;
+pub const RCODE_SERVFAIL: u8 = 2;
+pub const RCODE_NXDOMAIN: u8 = 3;
+pub const RCODE_REFUSED: u8 = 5;
+
+const HEADER_LEN: usize = 12;
+/// Largest UDP reply to a client that did not advertise EDNS
+pub const UDP_DEFAULT_PAYLOAD: usize = 512;
+
+fn malformed() -> Error {
+    Error::from_description("malformed DNS message")
+}
+
+fn u16_at(msg: &[u8], pos: usize) -> Result<u16, Error> {
+    msg.get(pos..pos + 2)
+        .map(|b| u16::from_be_bytes([b[0], b[1]]))
+        .ok_or_else(malformed)
+}
+
+fn u32_at(msg: &[u8], pos: usize) -> Result<u32, Error> {
+    msg.get(pos..pos + 4)
+        .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(malformed)
+}
+
+/// Decodes the (possibly compressed) name at `pos`. Returns the name and the
+/// position right after it in the message.
+fn read_name(msg: &[u8], mut pos: usize) -> Result<(String, usize), Error> {
+    let mut labels = vec![];
+    let mut end = None;
+    // Bounds the number of compression pointers followed
+    for _ in 0..128 {
+        let len = *msg.get(pos).ok_or_else(malformed)? as usize;
+        match len {
+            0 => {
+                let name = labels.join(".");
+                return Ok((name, end.unwrap_or(pos + 1)));
+            }
+            l if l & 0xc0 == 0xc0 => {
+                let ptr = (u16_at(msg, pos)? & 0x3fff) as usize;
+                end.get_or_insert(pos + 2);
+                pos = ptr;
+            }
+            l if l < 64 => {
+                let label = msg.get(pos + 1..pos + 1 + l).ok_or_else(malformed)?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                pos += 1 + l;
+            }
+            _ => return Err(malformed()),
+        }
+    }
+    Err(malformed())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Question {
+    pub name: String,
+    pub qtype: u16,
+    pub qclass: u16,
+}
+
+/// A query with exactly one question, which is what every resolver sends
+#[derive(Debug)]
+pub struct Query<'a> {
+    pub id: u16,
+    pub question: Question,
+    msg: &'a [u8],
+    question_end: usize,
+}
+
+impl<'a> Query<'a> {
+    pub fn parse(msg: &'a [u8]) -> Result<Self, Error> {
+        if msg.len() < HEADER_LEN || msg[2] & 0x80 != 0 || u16_at(msg, 4)? != 1 {
+            return Err(malformed());
+        }
+        let (name, pos) = read_name(msg, HEADER_LEN)?;
+        let question = Question {
+            name: name.to_ascii_lowercase(),
+            qtype: u16_at(msg, pos)?,
+            qclass: u16_at(msg, pos + 2)?,
+        };
+        Ok(Query {
+            id: u16_at(msg, 0)?,
+            question,
+            msg,
+            question_end: pos + 4,
+        })
+    }
+
+    /// The UDP payload size the client can take, from its EDNS OPT record
+    pub fn udp_payload_size(&self) -> usize {
+        records(self.msg)
+            .ok()
+            .and_then(|rs| rs.into_iter().find(|r| r.rtype == TYPE_OPT))
+            .map_or(UDP_DEFAULT_PAYLOAD, |r| {
+                (r.class as usize).max(UDP_DEFAULT_PAYLOAD)
+            })
+    }
+
+    /// Starts a reply: the query's header and question, with QR and RA set
+    fn reply_header(&self, rcode: u8, answers: u16) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.question_end + 64);
+        out.extend_from_slice(&self.id.to_be_bytes());
+        // Keep opcode and RD from the query
+        out.push(0x80 | (self.msg[2] & 0x79));
+        out.push(0x80 | (rcode & 0x0f));
+        out.extend_from_slice(&1u16.to_be_bytes());
+        out.extend_from_slice(&answers.to_be_bytes());
+        out.extend_from_slice(&[0, 0, 0, 0]);
+        out.extend_from_slice(&self.msg[HEADER_LEN..self.question_end]);
+        out
+    }
+
+    pub fn error_reply(&self, rcode: u8) -> Vec<u8> {
+        self.reply_header(rcode, 0)
+    }
+
+    /// Answers an A or AAAA question with the addresses of matching family.
+    /// Other question types get an empty (NODATA) answer.
+    pub fn address_reply(&self, addrs: &[IpAddr], ttl: u32) -> Vec<u8> {
+        let rdata = addrs
+            .iter()
+            .filter_map(|ip| match (ip, self.question.qtype) {
+                (IpAddr::V4(v4), TYPE_A) => Some(v4.octets().to_vec()),
+                (IpAddr::V6(v6), TYPE_AAAA) => Some(v6.octets().to_vec()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let mut out = self.reply_header(0, rdata.len() as u16);
+        for data in rdata {
+            // Pointer to the question name
+            out.extend_from_slice(&[0xc0, HEADER_LEN as u8]);
+            out.extend_from_slice(&self.question.qtype.to_be_bytes());
+            out.extend_from_slice(&CLASS_IN.to_be_bytes());
+            out.extend_from_slice(&ttl.to_be_bytes());
+            out.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            out.extend_from_slice(&data);
+        }
+        out
+    }
+}
+
+/// A resource record of any section, located in its message
+#[derive(Debug)]
+pub struct Record {
+    pub rtype: u16,
+    pub class: u16,
+    pub ttl_pos: usize,
+}
+
+impl Record {
+    pub fn ttl(&self, msg: &[u8]) -> Result<u32, Error> {
+        u32_at(msg, self.ttl_pos)
+    }
+}
+
+pub fn rcode(msg: &[u8]) -> u8 {
+    msg.get(3).map_or(RCODE_SERVFAIL, |b| b & 0x0f)
+}
+
+pub fn set_id(msg: &mut [u8], id: u16) {
+    msg[0..2].copy_from_slice(&id.to_be_bytes());
+}
+
+/// Marks a reply as truncated and strips everything after the question, so
+/// that a UDP client retries over TCP.
+pub fn truncate(msg: &[u8]) -> Result<Vec<u8>, Error> {
+    let (_, pos) = read_name(msg, HEADER_LEN)?;
+    let mut out = msg.get(..pos + 4).ok_or_else(malformed)?.to_vec();
+    out[2] |= 0x02;
+    out[6..12].copy_from_slice(&[0; 6]);
+    Ok(out)
+}
+
+/// All resource records of the answer, authority and additional sections
+pub fn records(msg: &[u8]) -> Result<Vec<Record>, Error> {
+    let qdcount = u16_at(msg, 4)?;
+    let rrcount = u16_at(msg, 6)? as usize + u16_at(msg, 8)? as usize + u16_at(msg, 10)? as usize;
+    let mut pos = HEADER_LEN;
+    for _ in 0..qdcount {
+        pos = read_name(msg, pos)?.1 + 4;
+    }
+    let mut records = Vec::with_capacity(rrcount);
+    for _ in 0..rrcount {
+        pos = read_name(msg, pos)?.1;
+        let rdlen = u16_at(msg, pos + 8)? as usize;
+        let end = pos + 10 + rdlen;
+        if end > msg.len() {
+            return Err(malformed());
+        }
+        records.push(Record {
+            rtype: u16_at(msg, pos)?,
+            class: u16_at(msg, pos + 2)?,
+            ttl_pos: pos + 4,
+        });
+        pos = end;
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+pub(crate) mod test_message {
+    use super::*;
+
+    /// A query as resolvers send it, RD set, optionally with an EDNS OPT
+    /// record advertising `payload`
+    pub(crate) fn query(id: u16, name: &str, qtype: u16, payload: Option<u16>) -> Vec<u8> {
+        let mut msg = id.to_be_bytes().to_vec();
+        msg.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, payload.is_some() as u8]);
+        for label in name.split('.') {
+            msg.push(label.len() as u8);
+            msg.extend_from_slice(label.as_bytes());
+        }
+        msg.push(0);
+        msg.extend_from_slice(&qtype.to_be_bytes());
+        msg.extend_from_slice(&CLASS_IN.to_be_bytes());
+        if let Some(payload) = payload {
+            msg.push(0);
+            msg.extend_from_slice(&TYPE_OPT.to_be_bytes());
+            msg.extend_from_slice(&payload.to_be_bytes());
+            msg.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+        }
+        msg
+    }
+
+    #[test]
+    fn parse_query() {
+        let msg = query(0x1234, "WWW.Example.com", TYPE_AAAA, Some(1232));
+        let q = Query::parse(&msg).unwrap();
+        assert_eq!(q.id, 0x1234);
+        assert_eq!(
+            q.question,
+            Question {
+                name: "www.example.com".to_string(),
+                qtype: TYPE_AAAA,
+                qclass: CLASS_IN,
+            }
+        );
+        assert_eq!(q.udp_payload_size(), 1232);
+        let plain = query(1, "example.com", TYPE_A, None);
+        assert_eq!(Query::parse(&plain).unwrap().udp_payload_size(), 512);
+        // Cut short, a reply, and two questions
+        assert!(Query::parse(&msg[..msg.len() - 20]).is_err());
+        let mut reply = plain.clone();
+        reply[2] |= 0x80;
+        assert!(Query::parse(&reply).is_err());
+        let mut two = plain;
+        two[5] = 2;
+        assert!(Query::parse(&two).is_err());
+    }
+
+    #[test]
+    fn compression_loops() {
+        let mut msg = query(1, "example.com", TYPE_A, None);
+        // The name points at itself
+        msg[12..14].copy_from_slice(&[0xc0, 12]);
+        assert!(Query::parse(&msg).is_err());
+    }
+
+    #[test]
+    fn address_reply_round_trip() {
+        let msg = query(7, "example.com", TYPE_A, None);
+        let q = Query::parse(&msg).unwrap();
+        let addrs = ["192.0.2.1".parse().unwrap(), "2001:db8::1".parse().unwrap()];
+        let reply = q.address_reply(&addrs, 60);
+        assert_eq!(u16_at(&reply, 0).unwrap(), 7);
+        // QR, RD and RA
+        assert_eq!(&reply[2..4], &[0x81, 0x80]);
+        assert_eq!(rcode(&reply), 0);
+        // Only the A record is answered
+        let rs = records(&reply).unwrap();
+        assert_eq!(rs.len(), 1);
+        assert_eq!((rs[0].rtype, rs[0].class), (TYPE_A, CLASS_IN));
+        assert_eq!(rs[0].ttl(&reply).unwrap(), 60);
+        assert_eq!(&reply[reply.len() - 4..], &[192, 0, 2, 1]);
+        // Its name is a pointer to the question
+        let (name, _) = read_name(&reply, rs[0].ttl_pos - 6).unwrap();
+        assert_eq!(name, "example.com");
+
+        let refused = q.error_reply(RCODE_REFUSED);
+        assert_eq!(rcode(&refused), RCODE_REFUSED);
+        assert!(records(&refused).unwrap().is_empty());
+        assert_eq!(Query::parse(&msg).unwrap().question, q.question);
+    }
+
+    #[test]
+    fn truncated_reply() {
+        let msg = query(7, "example.com", TYPE_A, None);
+        let q = Query::parse(&msg).unwrap();
+        let addrs = vec!["192.0.2.1".parse().unwrap(); 40];
+        let reply = q.address_reply(&addrs, 60);
+        assert!(reply.len() > q.udp_payload_size());
+        let short = truncate(&reply).unwrap();
+        assert_eq!(short.len(), msg.len());
+        assert_eq!(short[2] & 0x02, 0x02);
+        assert!(records(&short).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,423 @@
+//! A local DNS server, so that name lookups follow the routing rules instead
+//! of leaking to the local network. Queries received over UDP or TCP are
+//! forwarded over TCP, through the outgoing their name routes to, to an
+//! upstream resolver.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{debug, error, info};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::{watch, Semaphore};
+
+use crate::client_manager::{closed, ClientContext};
+use crate::config::DnsConfig;
+use crate::error::Error;
+use crate::incoming::ClientInfo;
+use crate::outgoing::{get_outgoing, Outgoing, OutgoingError};
+use crate::req_addr::ReqAddr;
+use crate::resolver::StaticAnswer;
+use crate::sockopt;
+
+mod cache;
+mod message;
+
+use cache::DnsCache;
+use message::{Query, CLASS_IN, RCODE_FORMERR, RCODE_REFUSED, RCODE_SERVFAIL, TYPE_A, TYPE_AAAA};
+
+/// TTL of answers taken from the hosts table
+const HOSTS_TTL: u32 = 60;
+/// Fake addresses stay mapped much longer than this, so clients keep asking
+/// and keep their mapping alive.
+const FAKE_IP_TTL: u32 = 1;
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(5);
+/// TCP clients silent for this long between (or within) queries are closed
+const CLIENT_TIMEOUT: Duration = UPSTREAM_TIMEOUT;
+/// UDP queries answered at a time; further datagrams wait in the socket
+const MAX_UDP_QUERIES: usize = 256;
+
+async fn read_tcp_message(stream: &mut (impl AsyncRead + Unpin)) -> Result<Vec<u8>, Error> {
+    let len = stream.read_u16().await? as usize;
+    let mut msg = vec![0; len];
+    stream.read_exact(&mut msg).await?;
+    Ok(msg)
+}
+
+async fn write_tcp_message(
+    stream: &mut (impl AsyncWrite + Unpin),
+    msg: &[u8],
+) -> Result<(), Error> {
+    let mut buf = Vec::with_capacity(msg.len() + 2);
+    buf.extend_from_slice(&(msg.len() as u16).to_be_bytes());
+    buf.extend_from_slice(msg);
+    stream.write_all(&buf).await?;
+    Ok(())
+}
+
+pub struct DnsServer {
+    listen_addr: SocketAddr,
+    udp: UdpSocket,
+    tcp: TcpListener,
+    upstream: ReqAddr,
+    ctx: Arc<ClientContext>,
+    cache: DnsCache,
+    udp_queries: Arc<Semaphore>,
+}
+
+impl DnsServer {
+    pub async fn from_cfg(conf: DnsConfig, ctx: Arc<ClientContext>) -> Result<Self, Error> {
+        let listen_addr = conf.listen_addr.into_addr()?;
+        Ok(DnsServer {
+            listen_addr,
+            udp: UdpSocket::bind(listen_addr).await?,
+            tcp: sockopt::bind_listener(listen_addr, &conf.socket)?,
+            upstream: conf.upstream.into_req_addr()?,
+            ctx,
+            cache: DnsCache::new(conf.cache_size),
+            udp_queries: Arc::new(Semaphore::new(MAX_UDP_QUERIES)),
+        })
+    }
+
+    /// Answers queries until `stop` is set. Queries in flight are then
+    /// dropped and TCP clients disconnected; the sockets close as soon as
+    /// their tasks notice.
+    pub async fn run(self, stop: watch::Receiver<bool>) {
+        info!("dns listening at {}", self.listen_addr);
+        let server = Arc::new(self);
+        tokio::select! {
+            _ = server.clone().serve_tcp(stop.clone()) => {}
+            _ = server.clone().serve_udp(stop.clone()) => {}
+            _ = closed(stop) => {}
+        }
+        info!("dns no longer listening at {}", server.listen_addr);
+    }
+
+    async fn serve_udp(self: Arc<Self>, stop: watch::Receiver<bool>) {
+        let mut buf = [0u8; 4096];
+        loop {
+            // Never closed
+            let permit = self.udp_queries.clone().acquire_owned().await.unwrap();
+            let (len, from) = match self.udp.recv_from(&mut buf).await {
+                Ok(r) => r,
+                Err(e) => {
+                    error!("dns udp receive error: {}", e);
+                    continue;
+                }
+            };
+            let msg = buf[..len].to_vec();
+            let server = self.clone();
+            let stop = stop.clone();
+            tokio::spawn(async move {
+                let _permit = permit;
+                let reply = match Query::parse(&msg) {
+                    Ok(query) => {
+                        let reply = tokio::select! {
+                            reply = server.handle(&query, &msg) => reply,
+                            _ = closed(stop) => return,
+                        };
+                        if reply.len() > query.udp_payload_size() {
+                            message::truncate(&reply).unwrap_or(reply)
+                        } else {
+                            reply
+                        }
+                    }
+                    Err(e) => return debug!("dns query from {} dropped: {}", from, e),
+                };
+                if let Err(e) = server.udp.send_to(&reply, from).await {
+                    error!("dns reply to {} failed: {}", from, e);
+                }
+            });
+        }
+    }
+
+    async fn serve_tcp(self: Arc<Self>, stop: watch::Receiver<bool>) {
+        loop {
+            match self.tcp.accept().await {
+                Ok((stream, from)) => {
+                    let server = self.clone();
+                    let stop = stop.clone();
+                    tokio::spawn(async move {
+                        tokio::select! {
+                            res = server.serve_tcp_client(stream) => {
+                                if let Err(e) = res {
+                                    debug!("dns tcp client {}: {}", from, e);
+                                }
+                            }
+                            _ = closed(stop) => {}
+                        }
+                    });
+                }
+                Err(e) => error!("dns tcp accept error: {}", e),
+            }
+        }
+    }
+
+    async fn serve_tcp_client(&self, mut stream: TcpStream) -> Result<(), Error> {
+        loop {
+            let msg =
+                match tokio::time::timeout(CLIENT_TIMEOUT, read_tcp_message(&mut stream)).await {
+                    Ok(Ok(msg)) => msg,
+                    // The client is done
+                    Ok(Err(Error::Io(e))) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                        return Ok(())
+                    }
+                    Ok(Err(e)) => return Err(e),
+                    Err(_) => Err(Error::from_description("timed out waiting for a query"))?,
+                };
+            let query = Query::parse(&msg)?;
+            let reply = self.handle(&query, &msg).await;
+            write_tcp_message(&mut stream, &reply).await?;
+        }
+    }
+
+    async fn handle(&self, query: &Query<'_>, msg: &[u8]) -> Vec<u8> {
+        let q = &query.question;
+        debug!("dns query {} type {}", q.name, q.qtype);
+        if q.qclass != CLASS_IN || q.name.is_empty() {
+            return query.error_reply(RCODE_FORMERR);
+        }
+        if q.qtype == TYPE_A || q.qtype == TYPE_AAAA {
+            if let StaticAnswer::Addrs(addrs) = self.ctx.resolver.lookup_static(&q.name) {
+                return query.address_reply(&addrs, HOSTS_TTL);
+            }
+            if let Some(pool) = &self.ctx.fake_ip {
+                // The pool is IPv4 only, AAAA gets an empty answer
+                let ip = pool.allocate(&q.name);
+                return query.address_reply(&[ip.into()], FAKE_IP_TTL);
+            }
+        }
+        if let Some(reply) = self.cache.get(q, query.id) {
+            return reply;
+        }
+        match self.forward(query, msg).await {
+            Ok(reply) => {
+                self.cache.put(q.clone(), &reply);
+                reply
+            }
+            Err(OutgoingError::ConnectionNotAllowed(e)) => {
+                debug!("dns query {} refused: {}", q.name, e);
+                query.error_reply(RCODE_REFUSED)
+            }
+            Err(e) => {
+                error!("dns query {} failed: {}", q.name, e);
+                query.error_reply(RCODE_SERVFAIL)
+            }
+        }
+    }
+
+    /// Sends the query to the upstream resolver through the outgoing the
+    /// queried name is routed to. GeoIP rules would need the name resolved,
+    /// possibly by this very server, so they are left out.
+    async fn forward(&self, query: &Query<'_>, msg: &[u8]) -> Result<Vec<u8>, OutgoingError> {
+        let req = ReqAddr::Domain(query.question.name.clone(), 53);
+        let entry = self.ctx.router.route_no_resolve(&req, None, None);
+        let outgoing = get_outgoing(entry.clone()).map_err(OutgoingError::GeneralFailure)?;
+        let exchange = async {
+            let mut stream = outgoing
+                .process_request(
+                    self.upstream.clone(),
+                    ClientInfo {
+                        internal: true,
+                        ..Default::default()
+                    },
+                )
+                .await?;
+            write_tcp_message(&mut stream, msg)
+                .await
+                .map_err(OutgoingError::GeneralFailure)?;
+            read_tcp_message(&mut stream)
+                .await
+                .map_err(OutgoingError::GeneralFailure)
+        };
+        tokio::time::timeout(UPSTREAM_TIMEOUT, exchange)
+            .await
+            .map_err(|_| {
+                OutgoingError::TimedOut(Error::from_description("dns upstream timed out"))
+            })?
+    }
+}
+
+#[cfg(test)]
+mod test_dns {
+    use super::*;
+    use crate::config::{OutgoingConfig, RuleConfig};
+    use crate::dns::message::test_message::query;
+    use crate::dns::message::{rcode, records};
+    use crate::resolver::Resolver;
+    use crate::rules::Router;
+    use crate::stats::Stats;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Answers every query with 192.0.2.1, counting them
+    async fn fake_upstream() -> (SocketAddr, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let count = Arc::new(AtomicUsize::new(0));
+        let seen = count.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let msg = read_tcp_message(&mut stream).await.unwrap();
+                seen.fetch_add(1, Ordering::Relaxed);
+                let reply = Query::parse(&msg)
+                    .unwrap()
+                    .address_reply(&["192.0.2.1".parse().unwrap()], 300);
+                write_tcp_message(&mut stream, &reply).await.unwrap();
+            }
+        });
+        (addr, count)
+    }
+
+    async fn server(upstream: SocketAddr) -> DnsServer {
+        #[derive(serde_derive::Deserialize)]
+        struct Tables {
+            outgoing: Vec<OutgoingConfig>,
+            rule: Vec<RuleConfig>,
+        }
+        let tables = toml::from_str::<Tables>(
+            r#"
+            [[outgoing]]
+            name = "direct"
+            type = "Direct"
+            # Not for the upstream, which would take it for a query
+            proxy_protocol = "V2"
+            # The upstream is on loopback
+            acl = { block_private = false }
+
+            [[outgoing]]
+            name = "block"
+            type = "Ignore"
+
+            [[rule]]
+            domain_suffix = ["ads.example"]
+            outgoing = "block"
+            "#,
+        )
+        .unwrap();
+        let router = Router::from_cfg(
+            tables.outgoing,
+            &tables.rule,
+            None,
+            Arc::new(Resolver::default()),
+            Arc::new(Stats::default()),
+        )
+        .unwrap();
+        DnsServer {
+            listen_addr: "127.0.0.1:0".parse().unwrap(),
+            udp: UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+            tcp: TcpListener::bind("127.0.0.1:0").await.unwrap(),
+            upstream: ReqAddr::IP(upstream),
+            ctx: Arc::new(ClientContext::for_test(router)),
+            cache: DnsCache::new(16),
+            udp_queries: Arc::new(Semaphore::new(MAX_UDP_QUERIES)),
+        }
+    }
+
+    #[tokio::test]
+    async fn forwards_and_caches() {
+        let (upstream, count) = fake_upstream().await;
+        let server = server(upstream).await;
+        for id in [1, 2] {
+            let msg = query(id, "www.example.com", TYPE_A, None);
+            let reply = server.handle(&Query::parse(&msg).unwrap(), &msg).await;
+            assert_eq!(u16::from_be_bytes([reply[0], reply[1]]), id);
+            assert_eq!(rcode(&reply), 0);
+            assert_eq!(records(&reply).unwrap().len(), 1);
+            assert_eq!(&reply[reply.len() - 4..], &[192, 0, 2, 1]);
+        }
+        // The second one came from the cache
+        assert_eq!(count.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn refused_by_rules() {
+        let (upstream, count) = fake_upstream().await;
+        let server = server(upstream).await;
+        let msg = query(1, "tracker.ads.example", TYPE_A, None);
+        let reply = server.handle(&Query::parse(&msg).unwrap(), &msg).await;
+        assert_eq!(rcode(&reply), RCODE_REFUSED);
+        assert_eq!(count.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn upstream_down() {
+        // Nothing listens there once the listener is gone
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let server = server(addr).await;
+        let msg = query(1, "www.example.com", TYPE_A, None);
+        let reply = server.handle(&Query::parse(&msg).unwrap(), &msg).await;
+        assert_eq!(rcode(&reply), RCODE_SERVFAIL);
+        // Failures are not cached
+        assert!(server
+            .cache
+            .get(&Query::parse(&msg).unwrap().question, 1)
+            .is_none());
+    }
+    #[tokio::test]
+    async fn stop_closes_sockets() {
+        let (upstream, _) = fake_upstream().await;
+        let server = server(upstream).await;
+        let udp = server.udp.local_addr().unwrap();
+        let tcp = server.tcp.local_addr().unwrap();
+        let (stop_tx, stop) = watch::channel(false);
+        let running = tokio::spawn(server.run(stop));
+        tokio::task::yield_now().await;
+        stop_tx.send(true).unwrap();
+        running.await.unwrap();
+        assert!(TcpStream::connect(tcp).await.is_err());
+        // Free to be taken again
+        UdpSocket::bind(udp).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn stop_closes_idle_clients() {
+        let (upstream, _) = fake_upstream().await;
+        let server = server(upstream).await;
+        let tcp = server.tcp.local_addr().unwrap();
+        let (stop_tx, stop) = watch::channel(false);
+        let running = tokio::spawn(server.run(stop));
+        let mut client = TcpStream::connect(tcp).await.unwrap();
+        // One answered query, then nothing
+        write_tcp_message(&mut client, &query(1, "www.example.com", TYPE_A, None))
+            .await
+            .unwrap();
+        read_tcp_message(&mut client).await.unwrap();
+        stop_tx.send(true).unwrap();
+        running.await.unwrap();
+        // Closed well before the client would time out
+        let mut rest = vec![];
+        tokio::time::timeout(Duration::from_secs(1), client.read_to_end(&mut rest))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(rest.is_empty());
+    }
+
+    #[tokio::test]
+    async fn silent_clients_time_out() {
+        let (upstream, _) = fake_upstream().await;
+        let server = Arc::new(server(upstream).await);
+        let (stop_tx, stop) = watch::channel(false);
+        let tcp = server.tcp.local_addr().unwrap();
+        tokio::spawn(server.clone().serve_tcp(stop));
+        let mut client = TcpStream::connect(tcp).await.unwrap();
+        // Half a length prefix
+        client.write_all(&[0]).await.unwrap();
+        let started = std::time::Instant::now();
+        let mut rest = vec![];
+        client.read_to_end(&mut rest).await.unwrap();
+        let took = started.elapsed();
+        assert!(
+            took >= CLIENT_TIMEOUT - Duration::from_millis(100)
+                && took < CLIENT_TIMEOUT + Duration::from_secs(1),
+            "{:?}",
+            took
+        );
+        drop(stop_tx);
+    }
+}
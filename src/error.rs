@@ -9,7 +9,12 @@ pub enum Error {
     Description(String),
     Outgoing(Box<OutgoingError>),
     Utf8(Utf8Error),
+    Regex(regex::Error),
+    GeoIp(maxminddb::MaxMindDBError),
     NotConnected,
+    /// A client did not finish its handshake in time
+    #[display(fmt = "handshake timed out")]
+    HandshakeTimeout,
     #[display(fmt = "Invalid SOCKS5 address type")]
     InvalidSocks5AddrType,
 }
@@ -0,0 +1,190 @@
+use std::collections::{BTreeSet, HashMap};
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use ipnet::Ipv4Net;
+use log::debug;
+
+use crate::error::Error;
+
+struct PoolState {
+    by_domain: HashMap<String, Ipv4Addr>,
+    by_ip: HashMap<Ipv4Addr, (String, Instant)>,
+    // The addresses of by_ip by expiry, least recently used first
+    by_expiry: BTreeSet<(Instant, Ipv4Addr)>,
+    // Offset of the first address never handed out
+    unused: u32,
+}
+
+impl PoolState {
+    fn touch(&mut self, ip: Ipv4Addr, expires: Instant) {
+        if let Some(entry) = self.by_ip.get_mut(&ip) {
+            self.by_expiry.remove(&(entry.1, ip));
+            entry.1 = expires;
+            self.by_expiry.insert((expires, ip));
+        }
+    }
+}
+
+/// Hands out addresses from a reserved range in place of real DNS answers,
+/// and remembers which domain each one stands for. A mapping expires when it
+/// has not been used for `ttl`; once the range runs out, the least recently
+/// used address is handed out again.
+pub struct FakeIpPool {
+    net: Ipv4Net,
+    ttl: Duration,
+    state: Mutex<PoolState>,
+}
+
+impl FakeIpPool {
+    pub fn new(range: &str, ttl: Duration) -> Result<Self, Error> {
+        let net = range
+            .parse::<Ipv4Net>()
+            .map_err(|_| Error::from_description(&format!("invalid fake ip range {}", range)))?
+            .trunc();
+        if net.prefix_len() > 30 {
+            Err(Error::from_description("fake ip range is too small"))?
+        }
+        Ok(FakeIpPool {
+            net,
+            ttl,
+            state: Mutex::new(PoolState {
+                by_domain: HashMap::new(),
+                by_ip: HashMap::new(),
+                by_expiry: BTreeSet::new(),
+                unused: 0,
+            }),
+        })
+    }
+
+    /// Usable addresses, leaving out the network and broadcast ones
+    fn size(&self) -> u32 {
+        (1u64 << (32 - self.net.prefix_len())) as u32 - 2
+    }
+
+    fn nth(&self, offset: u32) -> Ipv4Addr {
+        Ipv4Addr::from(u32::from(self.net.network()) + 1 + offset)
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match ip {
+            IpAddr::V4(v4) => self.net.contains(&v4),
+            IpAddr::V6(_) => false,
+        }
+    }
+
+    /// The fake address for `domain`, allocating one if needed
+    pub fn allocate(&self, domain: &str) -> Ipv4Addr {
+        self.allocate_at(domain, Instant::now())
+    }
+
+    fn allocate_at(&self, domain: &str, now: Instant) -> Ipv4Addr {
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        let mut state = self.state.lock().unwrap();
+        if let Some(&ip) = state.by_domain.get(&domain) {
+            state.touch(ip, now + self.ttl);
+            return ip;
+        }
+        // Fresh addresses first, then the least recently used one, which
+        // has expired unless the whole pool is in use
+        let ip = if state.unused < self.size() {
+            state.unused += 1;
+            self.nth(state.unused - 1)
+        } else {
+            let (_, ip) = state.by_expiry.pop_first().expect("the pool is full");
+            if let Some((old, _)) = state.by_ip.remove(&ip) {
+                state.by_domain.remove(&old);
+            }
+            ip
+        };
+        state.by_ip.insert(ip, (domain.clone(), now + self.ttl));
+        state.by_expiry.insert((now + self.ttl, ip));
+        debug!("fake ip {} -> {}", ip, domain);
+        state.by_domain.insert(domain, ip);
+        ip
+    }
+
+    /// The domain a fake address stands for, if the mapping is still alive
+    pub fn lookup(&self, ip: IpAddr) -> Option<String> {
+        let ip = match ip {
+            IpAddr::V4(v4) => v4,
+            IpAddr::V6(_) => return None,
+        };
+        self.lookup_at(ip, Instant::now())
+    }
+
+    fn lookup_at(&self, ip: Ipv4Addr, now: Instant) -> Option<String> {
+        let mut state = self.state.lock().unwrap();
+        let (domain, expires) = state.by_ip.get(&ip)?;
+        if *expires <= now {
+            return None;
+        }
+        let domain = domain.clone();
+        state.touch(ip, now + self.ttl);
+        Some(domain)
+    }
+}
+
+#[cfg(test)]
+mod test_fake_ip {
+    use super::*;
+
+    const TTL: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn maps_both_ways() {
+        let pool = FakeIpPool::new("198.18.0.0/15", TTL).unwrap();
+        let t0 = Instant::now();
+        let a = pool.allocate_at("a.example", t0);
+        let b = pool.allocate_at("b.example", t0);
+        assert_eq!(a, Ipv4Addr::new(198, 18, 0, 1));
+        assert_eq!(b, Ipv4Addr::new(198, 18, 0, 2));
+        assert_eq!(pool.allocate_at("A.example.", t0), a);
+        assert!(pool.contains(a.into()));
+        assert!(!pool.contains("198.20.0.1".parse().unwrap()));
+        assert_eq!(pool.lookup_at(b, t0).as_deref(), Some("b.example"));
+        assert_eq!(pool.lookup_at(Ipv4Addr::new(198, 18, 0, 3), t0), None);
+        // Lookups keep a mapping alive
+        assert!(pool.lookup_at(b, t0 + TTL / 2).is_some());
+        assert!(pool.lookup_at(b, t0 + TTL).is_some());
+        assert!(pool.lookup_at(a, t0 + TTL).is_none());
+    }
+
+    #[test]
+    fn reuses_least_recently_used() {
+        // Two usable addresses
+        let pool = FakeIpPool::new("192.0.2.0/30", TTL).unwrap();
+        let t0 = Instant::now();
+        let a = pool.allocate_at("a.example", t0);
+        let b = pool.allocate_at("b.example", t0 + Duration::from_secs(1));
+        pool.lookup_at(a, t0 + Duration::from_secs(2));
+        // b was used last the longest ago, even though it has not expired
+        assert_eq!(
+            pool.allocate_at("c.example", t0 + Duration::from_secs(3)),
+            b
+        );
+        let t1 = t0 + Duration::from_secs(4);
+        assert_eq!(pool.lookup_at(b, t1).as_deref(), Some("c.example"));
+        // b.example lost its address and gets a's, now the oldest
+        assert_eq!(pool.allocate_at("b.example", t1), a);
+        assert_eq!(pool.lookup_at(a, t1).as_deref(), Some("b.example"));
+        assert_eq!(pool.allocate_at("c.example", t1), b);
+    }
+
+    #[test]
+    fn full_pool_stays_consistent() {
+        let pool = FakeIpPool::new("192.0.2.0/29", TTL).unwrap();
+        let t0 = Instant::now();
+        for i in 0..100u64 {
+            pool.allocate_at(&format!("{}.example", i), t0 + Duration::from_secs(i));
+        }
+        let state = pool.state.lock().unwrap();
+        assert_eq!(state.by_ip.len(), 6);
+        assert_eq!(state.by_domain.len(), 6);
+        assert_eq!(state.by_expiry.len(), 6);
+        for (domain, ip) in &state.by_domain {
+            assert_eq!(&state.by_ip[ip].0, domain);
+        }
+    }
+}
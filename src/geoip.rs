@@ -0,0 +1,338 @@
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+use log::{error, info};
+use maxminddb::{geoip2, Reader};
+
+use crate::config::GeoIpConfig;
+use crate::error::Error;
+
+fn modified(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// A single `.mmdb` file that is swapped out when the file on disk changes.
+/// Lookups only take the current reader; loading happens on a blocking
+/// thread, see `GeoIp::run`.
+struct GeoDb {
+    path: String,
+    reader: RwLock<Arc<Reader<Vec<u8>>>>,
+    /// Modification time of the file at last load
+    loaded: Mutex<Option<SystemTime>>,
+}
+
+impl GeoDb {
+    fn open(path: &str) -> Result<Self, Error> {
+        let mtime = modified(path);
+        let reader = Reader::open_readfile(path)?;
+        info!("geoip database {} loaded", path);
+        Ok(GeoDb {
+            path: path.into(),
+            reader: RwLock::new(Arc::new(reader)),
+            loaded: Mutex::new(mtime),
+        })
+    }
+
+    /// Blocks while the file is read
+    fn reload_if_changed(&self) {
+        let mut loaded = self.loaded.lock().unwrap();
+        let mtime = modified(&self.path);
+        if mtime == *loaded {
+            return;
+        }
+        match Reader::open_readfile(&self.path) {
+            Ok(reader) => {
+                *self.reader.write().unwrap() = Arc::new(reader);
+                *loaded = mtime;
+                info!("geoip database {} reloaded", self.path);
+            }
+            Err(e) => error!("can't reload geoip database {}: {}", self.path, e),
+        }
+    }
+
+    fn reader(&self) -> Arc<Reader<Vec<u8>>> {
+        self.reader.read().unwrap().clone()
+    }
+}
+
+pub struct GeoIp {
+    country: Option<Arc<GeoDb>>,
+    asn: Option<Arc<GeoDb>>,
+    reload_interval: Duration,
+}
+
+impl GeoIp {
+    pub fn from_cfg(conf: &GeoIpConfig) -> Result<Self, Error> {
+        let open = |path: Option<&str>| path.map(GeoDb::open).transpose();
+        Ok(GeoIp {
+            country: open(conf.country_db.as_deref())?.map(Arc::new),
+            asn: open(conf.asn_db.as_deref())?.map(Arc::new),
+            reload_interval: Duration::from_secs(conf.reload_interval.max(1)),
+        })
+    }
+
+    /// Checks the files for changes every `reload_interval` and reloads
+    /// them in the background
+    pub async fn run(self: Arc<Self>) {
+        let mut ticker = tokio::time::interval(self.reload_interval);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            for db in self.country.iter().chain(&self.asn) {
+                let db = db.clone();
+                if let Err(e) = tokio::task::spawn_blocking(move || db.reload_if_changed()).await {
+                    error!("geoip reload failed: {}", e);
+                }
+            }
+        }
+    }
+
+    pub fn has_country(&self) -> bool {
+        self.country.is_some()
+    }
+
+    pub fn has_asn(&self) -> bool {
+        self.asn.is_some()
+    }
+
+    /// ISO code of the country the address is registered in
+    pub fn country(&self, ip: IpAddr) -> Option<String> {
+        let reader = self.country.as_ref()?.reader();
+        let country = reader.lookup::<geoip2::Country>(ip).ok()?;
+        country
+            .country
+            .or(country.registered_country)
+            .and_then(|c| c.iso_code)
+            .map(String::from)
+    }
+
+    pub fn asn(&self, ip: IpAddr) -> Option<u32> {
+        let reader = self.asn.as_ref()?.reader();
+        let asn = reader.lookup::<geoip2::Asn>(ip).ok()?;
+        asn.autonomous_system_number
+    }
+}
+
+/// Writes small MaxMind DB files for the tests, see
+/// <https://maxmind.github.io/MaxMind-DB/>
+#[cfg(test)]
+pub(crate) mod test_geoip {
+    use super::*;
+    use ipnet::Ipv4Net;
+
+    pub(crate) enum Value {
+        Str(String),
+        U16(u16),
+        U32(u32),
+        U64(u64),
+        Map(Vec<(&'static str, Value)>),
+        Array(Vec<Value>),
+    }
+
+    fn control(kind: u8, size: usize, out: &mut Vec<u8>) {
+        let (first, extra) = match size {
+            0..=28 => (size as u8, vec![]),
+            29..=284 => (29, vec![(size - 29) as u8]),
+            _ => (30, ((size - 285) as u16).to_be_bytes().to_vec()),
+        };
+        if kind <= 7 {
+            out.push(kind << 5 | first);
+        } else {
+            out.extend([first, kind - 7]);
+        }
+        out.extend(extra);
+    }
+
+    fn uint(kind: u8, n: u64, out: &mut Vec<u8>) {
+        let bytes = n.to_be_bytes();
+        let skip = bytes.iter().take_while(|b| **b == 0).count();
+        control(kind, 8 - skip, out);
+        out.extend(&bytes[skip..]);
+    }
+
+    fn encode(value: &Value, out: &mut Vec<u8>) {
+        match value {
+            Value::Str(s) => {
+                control(2, s.len(), out);
+                out.extend(s.as_bytes());
+            }
+            Value::U16(n) => uint(5, *n as u64, out),
+            Value::U32(n) => uint(6, *n as u64, out),
+            Value::U64(n) => uint(9, *n, out),
+            Value::Map(entries) => {
+                control(7, entries.len(), out);
+                for (key, value) in entries {
+                    encode(&Value::Str(key.to_string()), out);
+                    encode(value, out);
+                }
+            }
+            Value::Array(values) => {
+                control(11, values.len(), out);
+                for value in values {
+                    encode(value, out);
+                }
+            }
+        }
+    }
+
+    #[derive(Clone, Copy)]
+    enum Record {
+        Empty,
+        Node(usize),
+        Data(usize),
+    }
+
+    /// An IPv4 database of `db_type` with a record for each network
+    pub(crate) fn write_db(path: &str, db_type: &str, entries: Vec<(&str, Value)>) {
+        let mut nodes = vec![[Record::Empty; 2]];
+        let mut data = vec![];
+        for (net, value) in entries {
+            let net = net.parse::<Ipv4Net>().unwrap();
+            let bits = u32::from(net.network());
+            let mut node = 0;
+            for i in 0..net.prefix_len() as u32 {
+                let bit = (bits >> (31 - i) & 1) as usize;
+                if i + 1 == net.prefix_len() as u32 {
+                    nodes[node][bit] = Record::Data(data.len());
+                    break;
+                }
+                node = match nodes[node][bit] {
+                    Record::Node(next) => next,
+                    Record::Empty => {
+                        nodes.push([Record::Empty; 2]);
+                        nodes[node][bit] = Record::Node(nodes.len() - 1);
+                        nodes.len() - 1
+                    }
+                    Record::Data(_) => panic!("{} overlaps another network", net),
+                };
+            }
+            encode(&value, &mut data);
+        }
+        let count = nodes.len();
+        let mut out = vec![];
+        for record in nodes.iter().flatten() {
+            let value = match *record {
+                Record::Empty => count,
+                Record::Node(node) => node,
+                Record::Data(offset) => count + 16 + offset,
+            };
+            out.extend(&(value as u32).to_be_bytes()[1..]);
+        }
+        out.extend([0; 16]);
+        out.extend(data);
+        out.extend(b"\xab\xcd\xefMaxMind.com");
+        encode(
+            &Value::Map(vec![
+                ("node_count", Value::U32(count as u32)),
+                ("record_size", Value::U16(24)),
+                ("ip_version", Value::U16(4)),
+                ("database_type", Value::Str(db_type.to_string())),
+                ("languages", Value::Array(vec![])),
+                ("binary_format_major_version", Value::U16(2)),
+                ("binary_format_minor_version", Value::U16(0)),
+                ("build_epoch", Value::U64(0)),
+                ("description", Value::Map(vec![])),
+            ]),
+            &mut out,
+        );
+        std::fs::write(path, out).unwrap();
+    }
+
+    /// A country database placing each network in a country
+    pub(crate) fn write_country_db(path: &str, countries: &[(&str, &str)]) {
+        let entries = countries
+            .iter()
+            .map(|(net, code)| {
+                let country = Value::Map(vec![("iso_code", Value::Str(code.to_string()))]);
+                (*net, Value::Map(vec![("country", country)]))
+            })
+            .collect();
+        write_db(path, "GeoLite2-Country", entries);
+    }
+
+    pub(crate) fn write_asn_db(path: &str, asns: &[(&str, u32)]) {
+        let entries = asns
+            .iter()
+            .map(|(net, asn)| {
+                let asn = ("autonomous_system_number", Value::U32(*asn));
+                (*net, Value::Map(vec![asn]))
+            })
+            .collect();
+        write_db(path, "GeoLite2-ASN", entries);
+    }
+
+    pub(crate) fn temp_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("rocks-{}-{}.mmdb", name, std::process::id()));
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn country_and_asn() {
+        let (country_db, asn_db) = (temp_path("country"), temp_path("asn"));
+        write_country_db(
+            &country_db,
+            &[("192.0.2.0/24", "NZ"), ("198.51.100.0/25", "DE")],
+        );
+        write_asn_db(&asn_db, &[("203.0.113.0/24", 64500)]);
+        let geoip = GeoIp::from_cfg(&GeoIpConfig {
+            country_db: Some(country_db.clone()),
+            asn_db: Some(asn_db.clone()),
+            reload_interval: 60,
+        })
+        .unwrap();
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        assert_eq!(geoip.country(ip("192.0.2.77")).as_deref(), Some("NZ"));
+        assert_eq!(geoip.country(ip("198.51.100.1")).as_deref(), Some("DE"));
+        assert_eq!(geoip.country(ip("198.51.100.200")), None);
+        assert_eq!(geoip.country(ip("203.0.113.1")), None);
+        assert_eq!(geoip.asn(ip("203.0.113.1")), Some(64500));
+        assert_eq!(geoip.asn(ip("192.0.2.77")), None);
+        std::fs::remove_file(country_db).unwrap();
+        std::fs::remove_file(asn_db).unwrap();
+    }
+
+    #[tokio::test]
+    async fn reloads_changed_files() {
+        let path = temp_path("reload");
+        write_country_db(&path, &[("192.0.2.0/24", "NZ")]);
+        let geoip = Arc::new(
+            GeoIp::from_cfg(&GeoIpConfig {
+                country_db: Some(path.clone()),
+                asn_db: None,
+                reload_interval: 1,
+            })
+            .unwrap(),
+        );
+        let ip = "192.0.2.1".parse().unwrap();
+        let set_mtime = |mtime: SystemTime| {
+            let file = std::fs::File::options().write(true).open(&path).unwrap();
+            file.set_modified(mtime).unwrap();
+        };
+        let loaded = modified(&path).unwrap();
+
+        // Only the modification time counts
+        write_country_db(&path, &[("192.0.2.0/24", "AU")]);
+        set_mtime(loaded);
+        geoip.country.as_ref().unwrap().reload_if_changed();
+        assert_eq!(geoip.country(ip).as_deref(), Some("NZ"));
+
+        set_mtime(loaded + Duration::from_secs(10));
+        tokio::spawn(geoip.clone().run());
+        let reloaded = async {
+            while geoip.country(ip).as_deref() != Some("AU") {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(3), reloaded)
+            .await
+            .unwrap();
+
+        // A broken file leaves the last good database in place
+        std::fs::write(&path, b"not a database").unwrap();
+        set_mtime(loaded + Duration::from_secs(20));
+        geoip.country.as_ref().unwrap().reload_if_changed();
+        assert_eq!(geoip.country(ip).as_deref(), Some("AU"));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
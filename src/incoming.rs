@@ -1,28 +1,140 @@
-use crate::config::{IncomingConfig, IncomingType};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::config::{read_userfile, IncomingConfig, IncomingType};
 use crate::connection::Connection;
 use crate::error::Error;
+use crate::listener::Listener;
 use crate::outgoing::OutgoingError;
+use crate::proxy_protocol::ProxyProtocol;
 use crate::req_addr::ReqAddr;
 use crate::socks5::Socks5Incoming;
+use crate::systemd::InheritedFd;
 use crate::StandardFuture;
 
+/// What a client asks for
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Command {
+    Connect,
+    /// Look the domain up, without connecting
+    Resolve,
+    /// Look up the name of the IP address
+    ResolvePtr,
+}
+
+/// Where a request came from, for the outgoing that handles it
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ClientInfo {
+    pub peer: Option<SocketAddr>,
+    pub local: Option<SocketAddr>,
+    /// Made by this server on its own behalf (DNS forwarding), so there is
+    /// no client to tell the destination about
+    pub internal: bool,
+}
+
 pub trait Incoming {
-    type Client: IncomingClient + Send;
+    type Client: IncomingClient + Send + 'static;
     fn next_client<'a>(&'a mut self) -> StandardFuture<'a, Self::Client, Error>;
 }
 pub trait IncomingClient {
     type Connection: Connection + Send;
-    fn next_request<'a>(&'a mut self) -> StandardFuture<'a, ReqAddr, Error>;
+    /// The user name the client authenticated as, if any
+    fn user(&self) -> Option<&str>;
+    /// The client's address (as sent in a PROXY protocol header, if there
+    /// was one); `None` for clients without an IP address
+    fn peer_addr(&self) -> Option<SocketAddr>;
+    /// The address the client connected to
+    fn local_addr(&self) -> Option<SocketAddr>;
+    /// Whether the client is on a plain TCP connection, which can be
+    /// relayed without buffers
+    fn is_tcp(&self) -> bool {
+        false
+    }
+    fn info(&self) -> ClientInfo {
+        ClientInfo {
+            peer: self.peer_addr(),
+            local: self.local_addr(),
+            internal: false,
+        }
+    }
+    fn next_request<'a>(&'a mut self) -> StandardFuture<'a, (Command, ReqAddr), Error>;
     fn abort(self, err: OutgoingError, req: ReqAddr) -> StandardFuture<'static, (), Error>;
+    /// Answers a `Resolve` or `ResolvePtr` request
+    fn resolved(self, answer: ReqAddr) -> StandardFuture<'static, (), Error>;
+    /// Tells the client the request succeeded; `bound` is the local address
+    /// of the outgoing connection.
     fn ready_for_connect<'a>(
         &'a mut self,
-        req: ReqAddr,
+        bound: ReqAddr,
     ) -> StandardFuture<'a, Self::Connection, Error>;
 }
 
-pub async fn get_incoming<'a>(conf: IncomingConfig) -> Result<impl Incoming, Error> {
+/// The sockets inherited from systemd for an incoming if there are any,
+/// otherwise the configured addresses, bound here.
+fn listeners(conf: &IncomingConfig, inherited: Vec<InheritedFd>) -> Result<Vec<Listener>, Error> {
+    let mut listeners = vec![];
+    if !inherited.is_empty() {
+        for fd in inherited {
+            #[cfg(unix)]
+            listeners.push(Listener::from_fd(
+                fd,
+                conf.socket.clone(),
+                &conf.listen_unix,
+            )?);
+            #[cfg(not(unix))]
+            match fd {}
+        }
+        return Ok(listeners);
+    }
+    for addr in &conf.listen_addr {
+        listeners.push(Listener::bind_tcp(
+            addr.clone().into_addr()?,
+            conf.socket.clone(),
+        )?);
+    }
+    for unix in &conf.listen_unix {
+        #[cfg(unix)]
+        listeners.push(Listener::bind_unix(unix)?);
+        #[cfg(not(unix))]
+        Err(Error::from_description(&format!(
+            "can't listen on {}: Unix sockets are not supported",
+            unix.path
+        )))?
+    }
+    if listeners.is_empty() {
+        Err(Error::from_description(&format!(
+            "incoming {} has nowhere to listen",
+            conf.name
+        )))?
+    }
+    Ok(listeners)
+}
+
+pub async fn get_incoming(
+    conf: IncomingConfig,
+    inherited: Vec<InheritedFd>,
+    handshake_timeout: Option<Duration>,
+) -> Result<Vec<impl Incoming>, Error> {
     match conf.r#type {
-        IncomingType::Socks5 => Socks5Incoming::from_cfg(conf.listen_addr).await,
+        IncomingType::Socks5 => {
+            let users = conf
+                .userfile
+                .as_deref()
+                .map(read_userfile)
+                .transpose()?
+                .map(Arc::new);
+            let proxy = conf
+                .proxy_protocol
+                .as_ref()
+                .map(ProxyProtocol::from_cfg)
+                .transpose()?
+                .map(Arc::new);
+            listeners(&conf, inherited)?
+                .into_iter()
+                .map(|l| Socks5Incoming::new(l, users.clone(), proxy.clone(), handshake_timeout))
+                .collect()
+        }
         _ => Err(Error::from_description("Unsupported incoming type")),
     }
 }
@@ -0,0 +1,489 @@
+use std::fmt;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use log::{error, info};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+
+use crate::config::SocketConfig;
+#[cfg(unix)]
+use crate::config::UnixListenConfig;
+use crate::connection::Connection;
+use crate::error::Error;
+use crate::req_addr::ReqAddr;
+use crate::sockopt;
+use crate::systemd::InheritedFd;
+
+/// Either end of an accepted connection
+#[derive(Debug, Clone)]
+pub enum StreamAddr {
+    Ip(SocketAddr),
+    /// A socket path, or a description of an unnamed Unix peer
+    Unix(String),
+}
+impl fmt::Display for StreamAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StreamAddr::Ip(addr) => write!(f, "{}", addr),
+            StreamAddr::Unix(path) => write!(f, "unix:{}", path),
+        }
+    }
+}
+
+/// What `lookup_id` looks a name up as
+#[cfg(unix)]
+#[derive(Debug, Clone, Copy)]
+enum IdKind {
+    User,
+    Group,
+}
+#[cfg(unix)]
+impl fmt::Display for IdKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IdKind::User => write!(f, "user"),
+            IdKind::Group => write!(f, "group"),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn lookup_id(name: &str, what: IdKind) -> Result<u32, Error> {
+    if let Ok(id) = name.parse::<u32>() {
+        return Ok(id);
+    }
+    let cname = std::ffi::CString::new(name)
+        .map_err(|_| Error::from_description(&format!("invalid {} name {}", what, name)))?;
+    let mut buf = vec![0 as libc::c_char; 1024];
+    loop {
+        // The reentrant versions, as other threads may be looking names up
+        let (r, id) = unsafe {
+            match what {
+                IdKind::User => {
+                    let mut pw = std::mem::zeroed::<libc::passwd>();
+                    let mut found = std::ptr::null_mut();
+                    let r = libc::getpwnam_r(
+                        cname.as_ptr(),
+                        &mut pw,
+                        buf.as_mut_ptr(),
+                        buf.len(),
+                        &mut found,
+                    );
+                    (r, (!found.is_null()).then_some(pw.pw_uid))
+                }
+                IdKind::Group => {
+                    let mut gr = std::mem::zeroed::<libc::group>();
+                    let mut found = std::ptr::null_mut();
+                    let r = libc::getgrnam_r(
+                        cname.as_ptr(),
+                        &mut gr,
+                        buf.as_mut_ptr(),
+                        buf.len(),
+                        &mut found,
+                    );
+                    (r, (!found.is_null()).then_some(gr.gr_gid))
+                }
+            }
+        };
+        match r {
+            0 => {
+                return id
+                    .ok_or_else(|| Error::from_description(&format!("unknown {} {}", what, name)))
+            }
+            libc::ERANGE if buf.len() < 1 << 20 => buf.resize(buf.len() * 2, 0),
+            e => Err(std::io::Error::from_raw_os_error(e))?,
+        }
+    }
+}
+
+/// Makes way for a new socket at `path`: a socket left over from an earlier
+/// run is removed, one that still answers belongs to a running instance.
+#[cfg(unix)]
+fn remove_stale_socket(path: &str) -> Result<(), Error> {
+    use std::os::unix::fs::FileTypeExt;
+
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => {
+            if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                Err(Error::from_description(&format!(
+                    "{} is in use by another process",
+                    path
+                )))?
+            }
+            std::fs::remove_file(path)?;
+        }
+        Ok(_) => Err(Error::from_description(&format!(
+            "{} exists and is not a socket",
+            path
+        )))?,
+        Err(_) => {}
+    }
+    Ok(())
+}
+
+/// A socket an incoming accepts clients on
+#[derive(Debug)]
+pub enum Listener {
+    Tcp {
+        listener: TcpListener,
+        socket: SocketConfig,
+    },
+    #[cfg(unix)]
+    Unix {
+        listener: UnixListener,
+        path: String,
+        allow_uid: Vec<u32>,
+        allow_gid: Vec<u32>,
+        /// Remove the socket file when done
+        unlink: bool,
+    },
+}
+
+impl Listener {
+    pub fn bind_tcp(addr: SocketAddr, socket: SocketConfig) -> Result<Self, Error> {
+        Ok(Listener::Tcp {
+            listener: sockopt::bind_listener(addr, &socket)?,
+            socket,
+        })
+    }
+
+    /// Binds in a directory only we can enter and moves the socket into
+    /// place once its mode and owner are set, so no one can connect before.
+    #[cfg(unix)]
+    pub fn bind_unix(conf: &UnixListenConfig) -> Result<Self, Error> {
+        use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+        let path = std::path::Path::new(&conf.path);
+        let name = path.file_name().ok_or_else(|| {
+            Error::from_description(&format!("invalid socket path {}", conf.path))
+        })?;
+        let private = path.with_file_name(format!(
+            ".{}.{}.tmp",
+            name.to_string_lossy(),
+            std::process::id()
+        ));
+        std::fs::DirBuilder::new().mode(0o700).create(&private)?;
+        let temp = private.join("socket");
+        let listener = (|| {
+            let listener = UnixListener::bind(&temp)?;
+            if let Some(mode) = &conf.mode {
+                let mode = u32::from_str_radix(mode, 8).map_err(|_| {
+                    Error::from_description(&format!("invalid mode {} for {}", mode, conf.path))
+                })?;
+                std::fs::set_permissions(&temp, std::fs::Permissions::from_mode(mode))?;
+            }
+            if conf.owner.is_some() || conf.group.is_some() {
+                let uid = conf
+                    .owner
+                    .as_deref()
+                    .map(|o| lookup_id(o, IdKind::User))
+                    .transpose()?;
+                let gid = conf
+                    .group
+                    .as_deref()
+                    .map(|g| lookup_id(g, IdKind::Group))
+                    .transpose()?;
+                std::os::unix::fs::chown(&temp, uid, gid)?;
+            }
+            remove_stale_socket(&conf.path)?;
+            std::fs::rename(&temp, path)?;
+            Ok::<_, Error>(listener)
+        })();
+        let _ = std::fs::remove_dir_all(&private);
+        Ok(Listener::Unix {
+            listener: listener?,
+            path: conf.path.clone(),
+            allow_uid: conf.allow_uid.clone(),
+            allow_gid: conf.allow_gid.clone(),
+            unlink: true,
+        })
+    }
+
+    /// Wraps a listening socket inherited from systemd. A Unix socket gets
+    /// the peer checks of the `listen_unix` entry with the same path.
+    #[cfg(unix)]
+    pub fn from_fd(
+        fd: InheritedFd,
+        socket: SocketConfig,
+        unix: &[UnixListenConfig],
+    ) -> Result<Self, Error> {
+        let sock = socket2::Socket::from(fd);
+        if sock.r#type()? != socket2::Type::STREAM {
+            Err(Error::from_description(
+                "inherited socket is not a stream socket",
+            ))?
+        }
+        sock.set_nonblocking(true)?;
+        if sock.local_addr()?.as_socket().is_some() {
+            return Ok(Listener::Tcp {
+                listener: TcpListener::from_std(sock.into())?,
+                socket,
+            });
+        }
+        let listener =
+            std::os::unix::net::UnixListener::from(std::os::unix::io::OwnedFd::from(sock));
+        let path = listener
+            .local_addr()?
+            .as_pathname()
+            .map_or("(unnamed)".to_string(), |p| p.display().to_string());
+        let conf = unix.iter().find(|u| u.path == path);
+        Ok(Listener::Unix {
+            listener: UnixListener::from_std(listener)?,
+            path,
+            allow_uid: conf.map(|c| c.allow_uid.clone()).unwrap_or_default(),
+            allow_gid: conf.map(|c| c.allow_gid.clone()).unwrap_or_default(),
+            // The socket file belongs to systemd
+            unlink: false,
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<StreamAddr, Error> {
+        match self {
+            Listener::Tcp { listener, .. } => Ok(StreamAddr::Ip(listener.local_addr()?)),
+            #[cfg(unix)]
+            Listener::Unix { path, .. } => Ok(StreamAddr::Unix(path.clone())),
+        }
+    }
+
+    /// Waits for the next client. Unix peers that are not allowed are
+    /// turned away here.
+    pub async fn accept(&self) -> Result<(IncomingStream, StreamAddr), Error> {
+        match self {
+            Listener::Tcp { listener, socket } => {
+                let (stream, addr) = listener.accept().await?;
+                if let Err(e) = sockopt::apply_accepted(&stream, socket) {
+                    error!("can't set socket options for {}: {}", addr, e);
+                }
+                Ok((IncomingStream::Tcp(stream), StreamAddr::Ip(addr)))
+            }
+            #[cfg(unix)]
+            Listener::Unix {
+                listener,
+                path,
+                allow_uid,
+                allow_gid,
+                ..
+            } => loop {
+                let (stream, _) = listener.accept().await?;
+                let cred = match stream.peer_cred() {
+                    Ok(cred) => cred,
+                    Err(e) => {
+                        error!("can't check unix client on {}: {}", path, e);
+                        continue;
+                    }
+                };
+                let peer = format!(
+                    "{} (uid {}, gid {}, pid {})",
+                    path,
+                    cred.uid(),
+                    cred.gid(),
+                    cred.pid().map_or("?".to_string(), |p| p.to_string())
+                );
+                let allowed = (allow_uid.is_empty() && allow_gid.is_empty())
+                    || allow_uid.contains(&cred.uid())
+                    || allow_gid.contains(&cred.gid());
+                if !allowed {
+                    info!("unix client {} not allowed", peer);
+                    continue;
+                }
+                return Ok((IncomingStream::Unix(stream), StreamAddr::Unix(peer)));
+            },
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix {
+            path, unlink: true, ..
+        } = self
+        {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// The stream of an accepted client
+#[derive(Debug)]
+pub enum IncomingStream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+impl AsyncRead for IncomingStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            IncomingStream::Tcp(s) => Pin::new(s).poll_read(cx, buf),
+            #[cfg(unix)]
+            IncomingStream::Unix(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+impl AsyncWrite for IncomingStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            IncomingStream::Tcp(s) => Pin::new(s).poll_write(cx, buf),
+            #[cfg(unix)]
+            IncomingStream::Unix(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            IncomingStream::Tcp(s) => Pin::new(s).poll_flush(cx),
+            #[cfg(unix)]
+            IncomingStream::Unix(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            IncomingStream::Tcp(s) => Pin::new(s).poll_shutdown(cx),
+            #[cfg(unix)]
+            IncomingStream::Unix(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+impl Connection for IncomingStream {
+    type ReadHalf = tokio::io::ReadHalf<IncomingStream>;
+    type WriteHalf = tokio::io::WriteHalf<IncomingStream>;
+    /// Unix sockets have no IP address to report
+    fn l_addr(&self) -> Result<ReqAddr, Error> {
+        match self {
+            IncomingStream::Tcp(s) => s.l_addr(),
+            #[cfg(unix)]
+            IncomingStream::Unix(_) => Err(Error::from_description("unix socket has no ip")),
+        }
+    }
+    fn p_addr(&self) -> Result<ReqAddr, Error> {
+        match self {
+            IncomingStream::Tcp(s) => s.p_addr(),
+            #[cfg(unix)]
+            IncomingStream::Unix(_) => Err(Error::from_description("unix socket has no ip")),
+        }
+    }
+    fn split(self) -> (Self::ReadHalf, Self::WriteHalf) {
+        tokio::io::split(self)
+    }
+    fn as_tcp(&self) -> Option<&TcpStream> {
+        match self {
+            IncomingStream::Tcp(s) => Some(s),
+            #[cfg(unix)]
+            IncomingStream::Unix(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_listener {
+    use super::*;
+
+    #[cfg(unix)]
+    fn unix_conf(name: &str) -> UnixListenConfig {
+        let path = std::env::temp_dir().join(format!("rocks-{}-{}.sock", name, std::process::id()));
+        UnixListenConfig {
+            path: path.to_str().unwrap().to_string(),
+            mode: Some("600".to_string()),
+            owner: None,
+            group: None,
+            allow_uid: vec![],
+            allow_gid: vec![],
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn unix_socket_takeover() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let conf = unix_conf("takeover");
+        let first = Listener::bind_unix(&conf).unwrap();
+        let mode = std::fs::metadata(&conf.path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        // A running instance keeps its socket
+        assert!(Listener::bind_unix(&conf).is_err());
+        assert!(UnixStream::connect(&conf.path).await.is_ok());
+        drop(first);
+        // A stale one is replaced; std listeners leave their file behind
+        drop(std::os::unix::net::UnixListener::bind(&conf.path).unwrap());
+        assert!(std::path::Path::new(&conf.path).exists());
+        let second = Listener::bind_unix(&conf).unwrap();
+        assert!(UnixStream::connect(&conf.path).await.is_ok());
+        drop(second);
+        assert!(!std::path::Path::new(&conf.path).exists());
+        // Nothing but sockets is removed
+        std::fs::write(&conf.path, "data").unwrap();
+        assert!(Listener::bind_unix(&conf).is_err());
+        assert_eq!(std::fs::read(&conf.path).unwrap(), b"data");
+        std::fs::remove_file(&conf.path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn numeric_and_named_ids() {
+        assert_eq!(lookup_id("1234", IdKind::User).unwrap(), 1234);
+        let uid = unsafe { libc::getuid() };
+        assert_eq!(lookup_id(&user_name(uid), IdKind::User).unwrap(), uid);
+        let gid = unsafe { libc::getgid() };
+        assert_eq!(lookup_id(&group_name(gid), IdKind::Group).unwrap(), gid);
+        assert!(lookup_id("no-such-user-here", IdKind::User).is_err());
+        assert!(lookup_id("no-such-group-here", IdKind::Group).is_err());
+    }
+
+    /// The names of `uid` and `gid`, the other way around from `lookup_id`
+    #[cfg(unix)]
+    fn user_name(uid: u32) -> String {
+        let mut buf = vec![0 as libc::c_char; 1 << 16];
+        let mut pw = unsafe { std::mem::zeroed::<libc::passwd>() };
+        let mut found = std::ptr::null_mut();
+        let r = unsafe { libc::getpwuid_r(uid, &mut pw, buf.as_mut_ptr(), buf.len(), &mut found) };
+        assert!(r == 0 && !found.is_null(), "no name for uid {}", uid);
+        let name = unsafe { std::ffi::CStr::from_ptr(pw.pw_name) };
+        name.to_str().unwrap().to_string()
+    }
+    #[cfg(unix)]
+    fn group_name(gid: u32) -> String {
+        let mut buf = vec![0 as libc::c_char; 1 << 16];
+        let mut gr = unsafe { std::mem::zeroed::<libc::group>() };
+        let mut found = std::ptr::null_mut();
+        let r = unsafe { libc::getgrgid_r(gid, &mut gr, buf.as_mut_ptr(), buf.len(), &mut found) };
+        assert!(r == 0 && !found.is_null(), "no name for gid {}", gid);
+        let name = unsafe { std::ffi::CStr::from_ptr(gr.gr_name) };
+        name.to_str().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn accepts_when_socket_options_fail() {
+        // Linux refuses a keepalive idle time of 0 with EINVAL
+        let socket = SocketConfig {
+            keepalive_idle: Some(0),
+            ..Default::default()
+        };
+        let listener = Listener::Tcp {
+            listener: TcpListener::bind("127.0.0.1:0").await.unwrap(),
+            socket: socket.clone(),
+        };
+        let addr = match listener.local_addr().unwrap() {
+            StreamAddr::Ip(addr) => addr,
+            StreamAddr::Unix(_) => unreachable!(),
+        };
+        let probe = TcpStream::connect(addr).await.unwrap();
+        assert!(sockopt::apply_accepted(&probe, &socket).is_err());
+        let _client = TcpStream::connect(addr).await.unwrap();
+        // The probe's connection, then the client's
+        for _ in 0..2 {
+            assert!(listener.accept().await.is_ok());
+        }
+    }
+}
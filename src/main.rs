@@ -4,32 +4,70 @@ use clap::{Arg, Command};
 use futures::Future;
 use log::{error, info};
 use std::io::Read;
+use std::sync::Arc;
+use std::time::Duration;
 use std::{fs::File, pin::Pin};
+use tokio::sync::{mpsc, watch};
 
 pub type PinboxedSendFuture<'a, O> = Pin<Box<dyn Future<Output = O> + Send + 'a>>;
 pub type StandardFuture<'a, O, E> = Pin<Box<dyn Future<Output = Result<O, E>> + Send + 'a>>;
 
+mod acl;
+mod bufpool;
 mod client_manager;
 mod config;
+mod conn_limit;
 mod connection;
+mod dns;
 mod error;
+mod fake_ip;
+mod geoip;
 mod incoming;
+mod listener;
 mod outgoing;
+mod proxy_protocol;
+mod quota;
+mod ratelimit;
 mod req_addr;
+mod resolver;
 // mod rocks;
+mod rules;
+mod signals;
+mod sockopt;
 mod socks5;
+#[cfg(target_os = "linux")]
+mod splice;
+mod stats;
+mod systemd;
 // mod stream_wrap;
 // mod tls_stream;
 
 use config::RocksConfig;
-use incoming::{get_incoming, Incoming};
+use dns::DnsServer;
+use incoming::{get_incoming, Incoming, IncomingClient};
+use quota::Quotas;
+use ratelimit::RateLimiter;
+use resolver::Resolver;
+use rules::Router;
+use signals::Signals;
+use stats::Stats;
 
-use client_manager::handle_client;
+use bufpool::BufferPool;
+use client_manager::{handle_client, ClientContext, IncomingContext};
+use conn_limit::ConnLimiter;
+use fake_ip::FakeIpPool;
 
-#[tokio::main]
-async fn main() -> Result<(), error::Error> {
+fn main() -> Result<(), error::Error> {
     env_logger::init();
+    // Before any other thread exists, as it changes the environment
+    let listen_fds = systemd::take_listen_fds();
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(run(listen_fds))
+}
 
+async fn run(mut listen_fds: systemd::ListenFds) -> Result<(), error::Error> {
     let matches = Command::new("earthengineweb")
         .version("0.2.0")
         .about("Earth Engine's web site")
@@ -42,6 +80,17 @@ async fn main() -> Result<(), error::Error> {
                 .help("Use specified config file")
                 .takes_value(true),
         )
+        .subcommand(
+            Command::new("quota")
+                .about("Show or reset traffic quota usage")
+                .subcommand_required(true)
+                .subcommand(Command::new("show").about("Show usage in the current period"))
+                .subcommand(
+                    Command::new("reset")
+                        .about("Reset usage, of all users or of one")
+                        .arg(Arg::new("user").help("Only reset this user")),
+                ),
+        )
         .get_matches();
 
     let conf = matches.value_of("config").unwrap_or("config.toml");
@@ -54,15 +103,208 @@ async fn main() -> Result<(), error::Error> {
     })?;
     info!("config file read");
 
-    let mut incoming = get_incoming(conf.incoming).await?;
+    if let Some(("quota", m)) = matches.subcommand() {
+        let quota = conf
+            .quota
+            .ok_or_else(|| error::Error::from_description("no [quota] section in the config"))?;
+        return match m.subcommand() {
+            Some(("reset", m)) => quota::reset(&quota, m.value_of("user")),
+            _ => quota::show(&quota),
+        };
+    }
+
+    let incoming_names = conf
+        .incoming
+        .iter()
+        .map(|i| i.name.as_str())
+        .collect::<Vec<_>>();
+    systemd::assign_unnamed(&mut listen_fds, &incoming_names)?;
 
-    loop {
-        if let Ok(c) = incoming.next_client().await {
-            let o_conf = conf.outgoing.clone();
-            tokio::spawn(handle_client(c, o_conf));
-        } else {
-            break;
+    let resolver = Arc::new(Resolver::from_cfg(conf.hosts.as_ref())?);
+    let stats = Arc::new(Stats::default());
+    let router = Router::from_cfg(
+        conf.outgoing,
+        &conf.rules,
+        conf.geoip.as_ref(),
+        resolver.clone(),
+        stats.clone(),
+    )?;
+    let fake_ip = match &conf.dns {
+        Some(dns_conf) => dns_conf
+            .fake_ip_range
+            .as_deref()
+            .map(|r| FakeIpPool::new(r, Duration::from_secs(dns_conf.fake_ip_ttl)))
+            .transpose()?,
+        None => None,
+    };
+    let (close_tx, closing) = watch::channel(false);
+    let ctx = Arc::new(ClientContext {
+        router,
+        resolver,
+        fake_ip,
+        handshake_deadline: conf.timeouts.handshake_deadline(),
+        idle_timeout: conf.timeouts.idle(),
+        stats,
+        rate_limiter: RateLimiter::from_cfg(&conf.rate_limit),
+        quotas: conf.quota.map(Quotas::from_cfg).transpose()?.map(Arc::new),
+        buffers: Arc::new(BufferPool::from_cfg(&conf.buffers)?),
+        conn_limiter: Arc::new(ConnLimiter::from_cfg(conf.connections)),
+        closing,
+    });
+    if let Some(geoip) = ctx.router.geoip() {
+        tokio::spawn(geoip.run());
+    }
+    if let Some(quotas) = &ctx.quotas {
+        ctx.stats.subscribe(quotas.clone());
+        tokio::spawn(quotas.clone().run());
+    }
+    tokio::spawn(ctx.buffers.clone().run());
+    if conf.stats.summary_interval > 0 {
+        let interval = Duration::from_secs(conf.stats.summary_interval);
+        tokio::spawn(
+            ctx.stats
+                .clone()
+                .log_summaries(interval, ctx.buffers.clone()),
+        );
+    }
+    let mut signals = Signals::new()?;
+    let (stop_tx, stop) = watch::channel(false);
+    if let Some(dns_conf) = conf.dns {
+        let dns = DnsServer::from_cfg(dns_conf, ctx.clone()).await?;
+        tokio::spawn(dns.run(stop.clone()));
+    }
+    // Every client task holds a sender; recv returns None once all are gone
+    let (running, mut all_done) = mpsc::channel::<()>(1);
+    for inc_conf in conf.incoming {
+        let inc_ctx = Arc::new(IncomingContext::from_cfg(&inc_conf, &ctx.router)?);
+        let inherited = listen_fds.remove(&inc_conf.name).unwrap_or_default();
+        for incoming in get_incoming(inc_conf, inherited, conf.timeouts.handshake()).await? {
+            tokio::spawn(serve(
+                incoming,
+                ctx.clone(),
+                inc_ctx.clone(),
+                stop.clone(),
+                running.clone(),
+            ));
         }
     }
+    drop(running);
+    for name in listen_fds.keys() {
+        error!(
+            "no incoming named {} for the sockets passed by systemd",
+            name
+        );
+    }
+
+    let signal = signals.recv().await;
+    let drain = conf.timeouts.drain();
+    info!(
+        "{}: closing listeners, draining connections {}",
+        signal,
+        drain.map_or("until they finish".to_string(), |d| format!(
+            "for up to {:?}",
+            d
+        ))
+    );
+    let _ = stop_tx.send(true);
+    tokio::spawn(async move {
+        let signal = signals.recv().await;
+        error!("{} during shutdown: exiting now", signal);
+        std::process::exit(1);
+    });
+    let finished = match drain {
+        Some(drain) => tokio::time::timeout(drain, all_done.recv()).await.is_ok(),
+        None => {
+            all_done.recv().await;
+            true
+        }
+    };
+    if finished {
+        info!("all connections finished");
+    } else {
+        info!(
+            "closing {} connections that did not finish",
+            ctx.conn_limiter.active()
+        );
+        // They are recorded as they close, before usage is saved
+        let _ = close_tx.send(true);
+        if tokio::time::timeout(CLOSE_WAIT, all_done.recv())
+            .await
+            .is_err()
+        {
+            error!(
+                "{} connections did not close in time",
+                ctx.conn_limiter.active()
+            );
+        }
+    }
+    if let Some(quotas) = &ctx.quotas {
+        if let Err(e) = quotas.save_async().await {
+            error!("can't save quota state: {}", e);
+        }
+    }
+    info!("stats: {}; {}", ctx.stats.summary(), ctx.buffers.summary());
     Ok(())
 }
+
+/// How long connections get to close once told to after the drain
+const CLOSE_WAIT: Duration = Duration::from_secs(5);
+
+const ACCEPT_BACKOFF_MIN: Duration = Duration::from_millis(10);
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+
+/// Accepts clients until `stop` is set, then drops `incoming`, which closes
+/// its listener
+async fn serve(
+    mut incoming: impl Incoming,
+    ctx: Arc<ClientContext>,
+    inc_ctx: Arc<IncomingContext>,
+    mut stop: watch::Receiver<bool>,
+    running: mpsc::Sender<()>,
+) {
+    let mut backoff = ACCEPT_BACKOFF_MIN;
+    loop {
+        let next = tokio::select! {
+            next = incoming.next_client() => next,
+            _ = stop.changed() => break,
+        };
+        match next {
+            Ok(c) => {
+                backoff = ACCEPT_BACKOFF_MIN;
+                match ctx.conn_limiter.admit(c.peer_addr().map(|a| a.ip())) {
+                    Ok(permit) => {
+                        let (ctx, inc_ctx) = (ctx.clone(), inc_ctx.clone());
+                        let running = running.clone();
+                        tokio::spawn(async move {
+                            handle_client(c, ctx, inc_ctx).await;
+                            drop((permit, running));
+                        });
+                    }
+                    Err(reason) => {
+                        ctx.stats.rejected();
+                        info!(
+                            "rejecting {} on incoming {}: {}",
+                            c.peer_addr()
+                                .map_or("local client".to_string(), |a| a.to_string()),
+                            inc_ctx.name,
+                            reason
+                        );
+                    }
+                }
+            }
+            // Running out of file descriptors, for one, passes
+            Err(e) => {
+                error!(
+                    "incoming {} can't accept, retrying in {:?}: {}",
+                    inc_ctx.name, backoff, e
+                );
+                tokio::select! {
+                    _ = tokio::time::sleep(backoff) => {}
+                    _ = stop.changed() => break,
+                }
+                backoff = (backoff * 2).min(ACCEPT_BACKOFF_MAX);
+            }
+        }
+    }
+    info!("incoming {} no longer accepting", inc_ctx.name);
+}
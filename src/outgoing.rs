@@ -1,13 +1,23 @@
 use crate::connection::Connection;
-use log::error;
+use log::{error, info};
 use std::io::ErrorKind;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::task::{Context, Poll};
 use std::{future::Future, pin::Pin};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
 use tokio::net::TcpStream;
 
-use crate::config::{OutgoingConfig, OutgoingType};
+use crate::acl::Acl;
+use crate::config::{IgnoreReply, OutgoingConfig, OutgoingType};
 // use crate::connection::Connection;
 use crate::error::Error;
+use crate::incoming::ClientInfo;
+use crate::proxy_protocol;
 use crate::req_addr::ReqAddr;
+use crate::resolver::Resolver;
+use crate::sockopt::SourceBinding;
+use crate::stats::Stats;
 // use core::future::Future;
 // use failure::{Backtrace, Context, Error, Fail};
 // use std::io::ErrorKind;
@@ -20,8 +30,8 @@ use crate::req_addr::ReqAddr;
 pub enum OutgoingError {
     #[display(fmt = "GeneralFailure {}", _0)]
     GeneralFailure(Error),
-    //#[display(fmt = "ConnectionNotAllowed {}", _0)]
-    //ConnectionNotAllowed(Error),
+    #[display(fmt = "ConnectionNotAllowed {}", _0)]
+    ConnectionNotAllowed(Error),
     #[display(fmt = "NetworkUnreachable {}", _0)]
     NetworkUnreachable(Error),
     #[display(fmt = "HostUnreachable {}", _0)]
@@ -38,9 +48,9 @@ impl OutgoingError {
     fn general(e: Error) -> Self {
         OutgoingError::GeneralFailure(e)
     }
-    //     fn connection_not_allowed(e: Error) -> Self {
-    //         OutgoingError::ConnectionNotAllowed(Backtrace::new(), e)
-    //     }
+    fn connection_not_allowed(e: Error) -> Self {
+        OutgoingError::ConnectionNotAllowed(e)
+    }
     fn network_unreachable(e: Error) -> Self {
         OutgoingError::NetworkUnreachable(e)
     }
@@ -63,18 +73,72 @@ pub trait Outgoing {
     fn process_request(
         self,
         req: ReqAddr,
+        client: ClientInfo,
     ) -> Pin<Box<dyn Future<Output = Result<Self::Stream, OutgoingError>> + Send>>;
+    /// Looks a domain up the way this outgoing would when connecting
+    fn resolve(
+        self,
+        req: ReqAddr,
+    ) -> Pin<Box<dyn Future<Output = Result<Vec<IpAddr>, OutgoingError>> + Send>>;
+    fn resolve_ptr(
+        self,
+        ip: IpAddr,
+    ) -> Pin<Box<dyn Future<Output = Result<String, OutgoingError>> + Send>>;
 }
 
 #[derive(Clone, Debug)]
-struct DirectOutgoing;
+pub struct DirectOutgoing(Arc<OutgoingEntry>);
 impl Outgoing for DirectOutgoing {
     type Stream = TcpStream;
     fn process_request(
         self,
         req: ReqAddr,
+        client: ClientInfo,
     ) -> Pin<Box<dyn Future<Output = Result<Self::Stream, OutgoingError>> + Send>> {
-        Box::pin(self.process_request_impl(req))
+        Box::pin(self.process_request_impl(req, client))
+    }
+    fn resolve(
+        self,
+        req: ReqAddr,
+    ) -> Pin<Box<dyn Future<Output = Result<Vec<IpAddr>, OutgoingError>> + Send>> {
+        Box::pin(async move {
+            if let ReqAddr::Domain(domain, _) = &req {
+                if !self.0.acl.check_domain(domain) {
+                    return Err(self.not_allowed(&req, "domain"));
+                }
+            }
+            let addrs = self
+                .0
+                .resolver
+                .resolve(&req)
+                .await
+                .map_err(OutgoingError::host_unreachable)?;
+            // Only answer with addresses a connection would be allowed to
+            let ips = addrs
+                .into_iter()
+                .map(|a| a.ip())
+                .filter(|ip| self.0.acl.check_ip(*ip))
+                .collect::<Vec<_>>();
+            if ips.is_empty() {
+                return Err(self.not_allowed(&req, "address"));
+            }
+            Ok(ips)
+        })
+    }
+    fn resolve_ptr(
+        self,
+        ip: IpAddr,
+    ) -> Pin<Box<dyn Future<Output = Result<String, OutgoingError>> + Send>> {
+        Box::pin(async move {
+            if !self.0.acl.check_ip(ip) {
+                return Err(self.not_allowed(&ip, "address"));
+            }
+            self.0
+                .resolver
+                .reverse(ip)
+                .await
+                .map_err(OutgoingError::host_unreachable)
+        })
     }
 }
 
@@ -98,12 +162,48 @@ mod network_errors {
 }
 
 impl DirectOutgoing {
-    async fn process_request_impl(self, req: ReqAddr) -> Result<TcpStream, OutgoingError> {
-        let addr = req.resolve_local().map_err(|e| {
+    fn not_allowed(&self, req: &dyn std::fmt::Display, what: &str) -> OutgoingError {
+        info!("{} denied by acl of {} ({})", req, self.0.conf.name, what);
+        OutgoingError::connection_not_allowed(Error::from_description(&format!(
+            "{} not allowed",
+            what
+        )))
+    }
+
+    async fn process_request_impl(
+        self,
+        req: ReqAddr,
+        client: ClientInfo,
+    ) -> Result<TcpStream, OutgoingError> {
+        let acl = &self.0.acl;
+        let not_allowed = |what: &str| self.not_allowed(&req, what);
+        if !acl.check_port(req.port()) {
+            return Err(not_allowed("port"));
+        }
+        if let ReqAddr::Domain(domain, _) = &req {
+            if !acl.check_domain(domain) {
+                return Err(not_allowed("domain"));
+            }
+        }
+        let addrs = self.0.resolver.resolve(&req).await.map_err(|e| {
             error!("{} {}", req, e);
             OutgoingError::HostUnreachable(e)
         })?;
-        Ok(TcpStream::connect(&addr).await.map_err(|e| {
+        let addrs = addrs
+            .into_iter()
+            .filter(|a| self.0.source.can_reach(a))
+            .collect::<Vec<_>>();
+        if addrs.is_empty() {
+            return Err(OutgoingError::network_unreachable(Error::from_description(
+                "no bind_addr of the destination's address family",
+            )));
+        }
+        // Connect to the address that was checked, never resolve again
+        let addr = addrs
+            .into_iter()
+            .find(|a| acl.check_ip(a.ip()))
+            .ok_or_else(|| not_allowed("address"))?;
+        let mut stream = self.0.source.connect(addr).await.map_err(|e| {
             if e.kind() == ErrorKind::ConnectionRefused {
                 OutgoingError::connection_refused(e.into())
             } else {
@@ -118,13 +218,261 @@ impl DirectOutgoing {
                     _ => OutgoingError::general(e.into()),
                 }
             }
-        })?)
+        })?;
+        if let (Some(version), false) = (self.0.conf.proxy_protocol, client.internal) {
+            let header = proxy_protocol::encode_header(version, client.peer.zip(client.local));
+            stream
+                .write_all(&header)
+                .await
+                .map_err(|e| OutgoingError::general(e.into()))?;
+        }
+        Ok(stream)
     }
 }
 
-pub fn get_outgoing<'a>(conf: OutgoingConfig) -> Result<impl Outgoing + Send, Error> {
+/// A configured outgoing together with the state shared by all requests
+/// that are routed to it.
+#[derive(Debug)]
+pub struct OutgoingEntry {
+    pub conf: OutgoingConfig,
+    pub acl: Acl,
+    pub source: SourceBinding,
+    pub resolver: Arc<Resolver>,
+    pub stats: Arc<Stats>,
+}
+impl OutgoingEntry {
+    pub fn from_cfg(
+        conf: OutgoingConfig,
+        resolver: Arc<Resolver>,
+        stats: Arc<Stats>,
+    ) -> Result<Self, Error> {
+        Ok(OutgoingEntry {
+            acl: Acl::from_cfg(&conf.acl)?,
+            source: SourceBinding::from_cfg(&conf)?,
+            conf,
+            resolver,
+            stats,
+        })
+    }
+}
+
+/// Sink for requests routed to an `Ignore` outgoing
+#[derive(Clone, Debug)]
+pub struct IgnoreOutgoing(Arc<OutgoingEntry>);
+impl IgnoreOutgoing {
+    fn reject(&self, req: &ReqAddr) -> OutgoingError {
+        let hits = self.0.stats.ignored(req);
+        info!("{} ignored by {} ({} hits)", req, self.0.conf.name, hits);
+        let e = Error::from_description(&format!("ignored by {}", self.0.conf.name));
+        match self.0.conf.reply {
+            IgnoreReply::ConnectionNotAllowed => OutgoingError::connection_not_allowed(e),
+            // There is nothing to discard in a lookup
+            IgnoreReply::HostUnreachable | IgnoreReply::Discard => {
+                OutgoingError::host_unreachable(e)
+            }
+        }
+    }
+}
+impl Outgoing for IgnoreOutgoing {
+    type Stream = Blackhole;
+    fn process_request(
+        self,
+        req: ReqAddr,
+        _client: ClientInfo,
+    ) -> Pin<Box<dyn Future<Output = Result<Self::Stream, OutgoingError>> + Send>> {
+        Box::pin(async move {
+            match self.0.conf.reply {
+                IgnoreReply::Discard => {
+                    let hits = self.0.stats.ignored(&req);
+                    info!("{} discarded by {} ({} hits)", req, self.0.conf.name, hits);
+                    Ok(Blackhole(req))
+                }
+                _ => Err(self.reject(&req)),
+            }
+        })
+    }
+    fn resolve(
+        self,
+        req: ReqAddr,
+    ) -> Pin<Box<dyn Future<Output = Result<Vec<IpAddr>, OutgoingError>> + Send>> {
+        Box::pin(async move { Err(self.reject(&req)) })
+    }
+    fn resolve_ptr(
+        self,
+        ip: IpAddr,
+    ) -> Pin<Box<dyn Future<Output = Result<String, OutgoingError>> + Send>> {
+        Box::pin(async move { Err(self.reject(&ReqAddr::from_addr((ip, 0).into()))) })
+    }
+}
+
+/// Connection that reads as immediately closed and swallows all writes
+#[derive(Debug)]
+pub struct Blackhole(ReqAddr);
+impl AsyncRead for Blackhole {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        _buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+impl AsyncWrite for Blackhole {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Poll::Ready(Ok(buf.len()))
+    }
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+impl Connection for Blackhole {
+    type ReadHalf = tokio::io::ReadHalf<Blackhole>;
+    type WriteHalf = tokio::io::WriteHalf<Blackhole>;
+    fn l_addr(&self) -> Result<ReqAddr, Error> {
+        Ok(ReqAddr::from_addr(([0, 0, 0, 0], 0).into()))
+    }
+    fn p_addr(&self) -> Result<ReqAddr, Error> {
+        Ok(self.0.clone())
+    }
+    fn split(self) -> (Self::ReadHalf, Self::WriteHalf) {
+        tokio::io::split(self)
+    }
+}
+
+/// The stream of whichever outgoing handled a request
+#[derive(Debug)]
+pub enum OutgoingStream {
+    Tcp(TcpStream),
+    Blackhole(Blackhole),
+}
+impl AsyncRead for OutgoingStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            OutgoingStream::Tcp(s) => Pin::new(s).poll_read(cx, buf),
+            OutgoingStream::Blackhole(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+impl AsyncWrite for OutgoingStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            OutgoingStream::Tcp(s) => Pin::new(s).poll_write(cx, buf),
+            OutgoingStream::Blackhole(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            OutgoingStream::Tcp(s) => Pin::new(s).poll_flush(cx),
+            OutgoingStream::Blackhole(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            OutgoingStream::Tcp(s) => Pin::new(s).poll_shutdown(cx),
+            OutgoingStream::Blackhole(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+impl Connection for OutgoingStream {
+    type ReadHalf = tokio::io::ReadHalf<OutgoingStream>;
+    type WriteHalf = tokio::io::WriteHalf<OutgoingStream>;
+    fn l_addr(&self) -> Result<ReqAddr, Error> {
+        match self {
+            OutgoingStream::Tcp(s) => s.l_addr(),
+            OutgoingStream::Blackhole(s) => s.l_addr(),
+        }
+    }
+    fn p_addr(&self) -> Result<ReqAddr, Error> {
+        match self {
+            OutgoingStream::Tcp(s) => s.p_addr(),
+            OutgoingStream::Blackhole(s) => s.p_addr(),
+        }
+    }
+    fn split(self) -> (Self::ReadHalf, Self::WriteHalf) {
+        tokio::io::split(self)
+    }
+    fn as_tcp(&self) -> Option<&TcpStream> {
+        match self {
+            OutgoingStream::Tcp(s) => Some(s),
+            OutgoingStream::Blackhole(_) => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum AnyOutgoing {
+    Direct(DirectOutgoing),
+    Ignore(IgnoreOutgoing),
+}
+impl Outgoing for AnyOutgoing {
+    type Stream = OutgoingStream;
+    fn process_request(
+        self,
+        req: ReqAddr,
+        client: ClientInfo,
+    ) -> Pin<Box<dyn Future<Output = Result<Self::Stream, OutgoingError>> + Send>> {
+        match self {
+            AnyOutgoing::Direct(o) => {
+                let f = o.process_request(req, client);
+                Box::pin(async move { f.await.map(OutgoingStream::Tcp) })
+            }
+            AnyOutgoing::Ignore(o) => {
+                let f = o.process_request(req, client);
+                Box::pin(async move { f.await.map(OutgoingStream::Blackhole) })
+            }
+        }
+    }
+    fn resolve(
+        self,
+        req: ReqAddr,
+    ) -> Pin<Box<dyn Future<Output = Result<Vec<IpAddr>, OutgoingError>> + Send>> {
+        match self {
+            AnyOutgoing::Direct(o) => o.resolve(req),
+            AnyOutgoing::Ignore(o) => o.resolve(req),
+        }
+    }
+    fn resolve_ptr(
+        self,
+        ip: IpAddr,
+    ) -> Pin<Box<dyn Future<Output = Result<String, OutgoingError>> + Send>> {
+        match self {
+            AnyOutgoing::Direct(o) => o.resolve_ptr(ip),
+            AnyOutgoing::Ignore(o) => o.resolve_ptr(ip),
+        }
+    }
+}
+
+/// Turns away an outgoing that `get_outgoing` can't build, so that routing
+/// to it fails when the config is read rather than on every request
+pub fn check_implemented(conf: &OutgoingConfig) -> Result<(), Error> {
     match conf.r#type {
-        OutgoingType::Direct => Ok(DirectOutgoing),
+        OutgoingType::Direct | OutgoingType::Ignore => Ok(()),
+        OutgoingType::Rocks => Err(Error::from_description(&format!(
+            "outgoing {} has type {:?}, which is not implemented yet",
+            conf.name, conf.r#type
+        )))?,
+    }
+}
+
+pub fn get_outgoing(entry: Arc<OutgoingEntry>) -> Result<AnyOutgoing, Error> {
+    match entry.conf.r#type {
+        OutgoingType::Direct => Ok(AnyOutgoing::Direct(DirectOutgoing(entry))),
+        OutgoingType::Ignore => Ok(AnyOutgoing::Ignore(IgnoreOutgoing(entry))),
         _ => Err(Error::from_description("Unsupported outgoing type")),
     }
 }
@@ -0,0 +1,312 @@
+//! The PROXY protocol (v1 text and v2 binary), which load balancers put in
+//! front of a connection to pass on the original client address.
+//! <https://www.haproxy.org/download/2.8/doc/proxy-protocol.txt>
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use ipnet::IpNet;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::config::{ProxyProtocolConfig, ProxyProtocolVersion};
+use crate::error::Error;
+use crate::rules::parse_cidr;
+
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+/// Longest v1 header, CRLF included
+const V1_MAX_LEN: usize = 107;
+/// Longest v2 address block accepted. The addresses take at most 216
+/// bytes; the rest is for TLVs, which are not used.
+const V2_MAX_LEN: usize = 1024;
+
+const V2_CMD_LOCAL: u8 = 0x20;
+const V2_CMD_PROXY: u8 = 0x21;
+const V2_TCP4: u8 = 0x11;
+const V2_TCP6: u8 = 0x21;
+
+fn invalid(what: &str) -> Error {
+    Error::from_description(&format!("invalid PROXY protocol header: {}", what))
+}
+
+/// The sources that sit in front of an incoming and send PROXY headers
+#[derive(Debug)]
+pub struct ProxyProtocol {
+    trusted: Vec<IpNet>,
+}
+
+impl ProxyProtocol {
+    pub fn from_cfg(conf: &ProxyProtocolConfig) -> Result<Self, Error> {
+        Ok(ProxyProtocol {
+            trusted: conf
+                .trusted
+                .iter()
+                .map(|c| parse_cidr(c))
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    pub fn trusts(&self, ip: IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            v4 => v4,
+        };
+        self.trusted.iter().any(|n| n.contains(&ip))
+    }
+}
+
+/// Reads the header at the start of a connection. Returns the original
+/// source and destination, or `None` when the header carries no addresses
+/// (v2 `LOCAL`, v1 `UNKNOWN`, non-TCP families).
+pub async fn read_header(
+    stream: &mut (impl AsyncRead + Unpin),
+) -> Result<Option<(SocketAddr, SocketAddr)>, Error> {
+    // Shorter than any v1 header, so it never reads past one
+    let mut start = [0u8; 12];
+    stream.read_exact(&mut start).await?;
+    if &start == V2_SIGNATURE {
+        read_v2(stream).await
+    } else if start.starts_with(b"PROXY ") {
+        let mut line = start.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() == V1_MAX_LEN {
+                return Err(invalid("v1 line too long"));
+            }
+            line.push(stream.read_u8().await?);
+        }
+        parse_v1(&line)
+    } else {
+        Err(invalid("missing"))
+    }
+}
+
+fn parse_v1(line: &[u8]) -> Result<Option<(SocketAddr, SocketAddr)>, Error> {
+    let line = std::str::from_utf8(&line[..line.len() - 2])?;
+    let fields = line.split(' ').collect::<Vec<_>>();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", proto @ ("TCP4" | "TCP6"), src, dst, sport, dport] => {
+            let ip = |s: &str| s.parse::<IpAddr>().map_err(|_| invalid("v1 address"));
+            let port = |s: &str| s.parse::<u16>().map_err(|_| invalid("v1 port"));
+            let (src, dst) = (ip(src)?, ip(dst)?);
+            if src.is_ipv4() != (*proto == "TCP4") || dst.is_ipv4() != (*proto == "TCP4") {
+                return Err(invalid("v1 address family"));
+            }
+            Ok(Some((
+                SocketAddr::new(src, port(sport)?),
+                SocketAddr::new(dst, port(dport)?),
+            )))
+        }
+        _ => Err(invalid("v1 format")),
+    }
+}
+
+async fn read_v2(
+    stream: &mut (impl AsyncRead + Unpin),
+) -> Result<Option<(SocketAddr, SocketAddr)>, Error> {
+    let mut head = [0u8; 4];
+    stream.read_exact(&mut head).await?;
+    let len = u16::from_be_bytes([head[2], head[3]]) as usize;
+    if len > V2_MAX_LEN {
+        return Err(invalid("v2 header too long"));
+    }
+    let mut body = vec![0; len];
+    stream.read_exact(&mut body).await?;
+    parse_v2(head[0], head[1], &body)
+}
+
+fn parse_v2(
+    ver_cmd: u8,
+    family: u8,
+    body: &[u8],
+) -> Result<Option<(SocketAddr, SocketAddr)>, Error> {
+    let port = |i: usize| u16::from_be_bytes([body[i], body[i + 1]]);
+    match ver_cmd {
+        V2_CMD_LOCAL => Ok(None),
+        V2_CMD_PROXY => match family {
+            V2_TCP4 if body.len() >= 12 => {
+                let src = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+                let dst = Ipv4Addr::new(body[4], body[5], body[6], body[7]);
+                Ok(Some((
+                    SocketAddr::new(src.into(), port(8)),
+                    SocketAddr::new(dst.into(), port(10)),
+                )))
+            }
+            V2_TCP6 if body.len() >= 36 => {
+                let ip = |i: usize| {
+                    let mut octets = [0u8; 16];
+                    octets.copy_from_slice(&body[i..i + 16]);
+                    Ipv6Addr::from(octets)
+                };
+                Ok(Some((
+                    SocketAddr::new(ip(0).into(), port(32)),
+                    SocketAddr::new(ip(16).into(), port(34)),
+                )))
+            }
+            V2_TCP4 | V2_TCP6 => Err(invalid("v2 address block too short")),
+            // UDP and Unix sources say nothing useful about a TCP client
+            _ => Ok(None),
+        },
+        _ => Err(invalid("v2 version or command")),
+    }
+}
+
+/// Unmaps IPv4-mapped addresses, and maps IPv4 to IPv6 when the other
+/// address is IPv6, so that both are of one family.
+fn same_family(src: SocketAddr, dst: SocketAddr) -> (SocketAddr, SocketAddr) {
+    let unmap = |a: SocketAddr| match a.ip() {
+        IpAddr::V6(v6) => v6
+            .to_ipv4_mapped()
+            .map_or(a, |v4| SocketAddr::new(v4.into(), a.port())),
+        IpAddr::V4(_) => a,
+    };
+    let map = |a: SocketAddr| match a.ip() {
+        IpAddr::V4(v4) => SocketAddr::new(v4.to_ipv6_mapped().into(), a.port()),
+        IpAddr::V6(_) => a,
+    };
+    let (src, dst) = (unmap(src), unmap(dst));
+    if src.is_ipv4() == dst.is_ipv4() {
+        (src, dst)
+    } else {
+        (map(src), map(dst))
+    }
+}
+
+/// The header announcing a connection from `src` to `dst`, or one without
+/// addresses when the client has none (a Unix socket client).
+pub fn encode_header(
+    version: ProxyProtocolVersion,
+    addrs: Option<(SocketAddr, SocketAddr)>,
+) -> Vec<u8> {
+    let addrs = addrs.map(|(src, dst)| same_family(src, dst));
+    match version {
+        ProxyProtocolVersion::V1 => match addrs {
+            Some((src, dst)) => format!(
+                "PROXY {} {} {} {} {}\r\n",
+                if src.is_ipv4() { "TCP4" } else { "TCP6" },
+                src.ip(),
+                dst.ip(),
+                src.port(),
+                dst.port()
+            )
+            .into_bytes(),
+            None => b"PROXY UNKNOWN\r\n".to_vec(),
+        },
+        ProxyProtocolVersion::V2 => {
+            let mut out = V2_SIGNATURE.to_vec();
+            let mut body = vec![];
+            let (cmd, family) = match addrs {
+                Some((src, dst)) => {
+                    let family = match (src.ip(), dst.ip()) {
+                        (IpAddr::V4(s), IpAddr::V4(d)) => {
+                            body.extend_from_slice(&s.octets());
+                            body.extend_from_slice(&d.octets());
+                            V2_TCP4
+                        }
+                        (s, d) => {
+                            let v6 = |ip: IpAddr| match ip {
+                                IpAddr::V6(v6) => v6,
+                                IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+                            };
+                            body.extend_from_slice(&v6(s).octets());
+                            body.extend_from_slice(&v6(d).octets());
+                            V2_TCP6
+                        }
+                    };
+                    body.extend_from_slice(&src.port().to_be_bytes());
+                    body.extend_from_slice(&dst.port().to_be_bytes());
+                    (V2_CMD_PROXY, family)
+                }
+                None => (V2_CMD_LOCAL, 0),
+            };
+            out.extend_from_slice(&[cmd, family]);
+            out.extend_from_slice(&(body.len() as u16).to_be_bytes());
+            out.extend_from_slice(&body);
+            out
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_proxy_protocol {
+    use super::*;
+
+    async fn read(header: &[u8]) -> Result<Option<(SocketAddr, SocketAddr)>, Error> {
+        let mut stream = header;
+        read_header(&mut stream).await
+    }
+
+    fn addrs(src: &str, dst: &str) -> Option<(SocketAddr, SocketAddr)> {
+        Some((src.parse().unwrap(), dst.parse().unwrap()))
+    }
+
+    #[tokio::test]
+    async fn v1() {
+        let header = b"PROXY TCP4 192.0.2.1 198.51.100.7 51234 1080\r\n";
+        assert_eq!(
+            read(header).await.unwrap(),
+            addrs("192.0.2.1:51234", "198.51.100.7:1080")
+        );
+        let header = b"PROXY TCP6 2001:db8::1 2001:db8::2 51234 1080\r\n";
+        assert_eq!(
+            read(header).await.unwrap(),
+            addrs("[2001:db8::1]:51234", "[2001:db8::2]:1080")
+        );
+        assert_eq!(read(b"PROXY UNKNOWN\r\n").await.unwrap(), None);
+        assert!(read(b"PROXY TCP4 2001:db8::1 192.0.2.1 1 2\r\n")
+            .await
+            .is_err());
+        assert!(read(b"GET / HTTP/1.1\r\n\r\n").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn v2() {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[V2_CMD_PROXY, V2_TCP4, 0, 12]);
+        header.extend_from_slice(&[192, 0, 2, 1, 198, 51, 100, 7, 0xc8, 0x22, 0x04, 0x38]);
+        assert_eq!(
+            read(&header).await.unwrap(),
+            addrs("192.0.2.1:51234", "198.51.100.7:1080")
+        );
+
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[V2_CMD_LOCAL, 0, 0, 0]);
+        assert_eq!(read(&header).await.unwrap(), None);
+
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[V2_CMD_PROXY, V2_TCP4, 0xff, 0xff]);
+        header.resize(header.len() + 0xffff, 0);
+        assert!(read(&header).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn encoded_headers_read_back() {
+        for version in [ProxyProtocolVersion::V1, ProxyProtocolVersion::V2] {
+            let v4 = addrs("192.0.2.1:51234", "198.51.100.7:1080");
+            assert_eq!(read(&encode_header(version, v4)).await.unwrap(), v4);
+            let v6 = addrs("[2001:db8::1]:51234", "[2001:db8::2]:1080");
+            assert_eq!(read(&encode_header(version, v6)).await.unwrap(), v6);
+            let mixed = addrs("192.0.2.1:51234", "[2001:db8::2]:1080");
+            let mapped = addrs("[::ffff:192.0.2.1]:51234", "[2001:db8::2]:1080");
+            assert_eq!(read(&encode_header(version, mixed)).await.unwrap(), mapped);
+            assert_eq!(read(&encode_header(version, None)).await.unwrap(), None);
+        }
+        assert_eq!(
+            encode_header(
+                ProxyProtocolVersion::V1,
+                addrs("[::ffff:192.0.2.1]:51234", "198.51.100.7:1080")
+            ),
+            b"PROXY TCP4 192.0.2.1 198.51.100.7 51234 1080\r\n"
+        );
+    }
+
+    #[test]
+    fn trusted_sources() {
+        let pp = ProxyProtocol::from_cfg(&ProxyProtocolConfig {
+            trusted: vec!["10.0.0.0/8".to_string(), "::1".to_string()],
+        })
+        .unwrap();
+        assert!(pp.trusts("10.1.2.3".parse().unwrap()));
+        assert!(pp.trusts("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(pp.trusts("::1".parse().unwrap()));
+        assert!(!pp.trusts("192.0.2.1".parse().unwrap()));
+    }
+}
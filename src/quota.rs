@@ -0,0 +1,399 @@
+//! Traffic quotas: bytes per user and day or month (UTC), kept in a state
+//! file so that usage survives restarts.
+//!
+//! The server adds the traffic of connections as it is relayed and writes
+//! the file every `save_interval`. When the file was changed by someone else in
+//! the meantime (`rocks quota reset`), it is read back first and the traffic
+//! since the last save is added on top.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::{error, info};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::config::{QuotaConfig, QuotaPeriod};
+use crate::error::Error;
+use crate::outgoing::OutgoingError;
+use crate::stats::Subscriber;
+
+/// A user's traffic in one period
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Usage {
+    pub period: String,
+    pub used: u64,
+}
+
+/// The contents of the state file
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct QuotaState {
+    #[serde(default)]
+    pub users: BTreeMap<String, Usage>,
+}
+
+impl QuotaState {
+    pub fn read(path: &str) -> Result<Self, Error> {
+        match std::fs::read(path) {
+            Ok(content) => Ok(toml::from_slice(&content)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(QuotaState::default()),
+            Err(e) => Err(e)?,
+        }
+    }
+
+    /// Replaces the file in one step, so readers never see half of it. The
+    /// temporary file is named for this write alone, since the server and
+    /// `rocks quota reset` may write at the same time.
+    pub fn write(&self, path: &str) -> Result<(), Error> {
+        let content = toml::to_string(self)
+            .map_err(|e| Error::from_description(&format!("can't encode quota state: {}", e)))?;
+        let tmp = format!(
+            "{}.{}.{}.tmp",
+            path,
+            std::process::id(),
+            uuid::Uuid::new_v4().to_simple()
+        );
+        let written = std::fs::write(&tmp, content).and_then(|_| std::fs::rename(&tmp, path));
+        if written.is_err() {
+            let _ = std::fs::remove_file(&tmp);
+        }
+        Ok(written?)
+    }
+
+    /// What `user` has used in `period`
+    pub fn used(&self, user: &str, period: &str) -> u64 {
+        self.users
+            .get(user)
+            .filter(|u| u.period == period)
+            .map_or(0, |u| u.used)
+    }
+
+    fn add(&mut self, user: &str, period: &str, bytes: u64) {
+        let usage = self.users.entry(user.to_string()).or_default();
+        if usage.period != period {
+            *usage = Usage {
+                period: period.to_string(),
+                used: 0,
+            };
+        }
+        usage.used += bytes;
+    }
+}
+
+/// Days since the epoch to a (year, month, day) date, see
+/// <https://howardhinnant.github.io/date_algorithms.html#civil_from_days>
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month as u32, day as u32)
+}
+
+/// The name of the period `time` falls in, like `2024-02` or `2024-02-29`
+pub fn period_of(period: QuotaPeriod, time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    match period {
+        QuotaPeriod::Daily => format!("{:04}-{:02}-{:02}", year, month, day),
+        QuotaPeriod::Monthly => format!("{:04}-{:02}", year, month),
+    }
+}
+
+fn mtime(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Usage as the server sees it
+struct Tracked {
+    state: QuotaState,
+    /// Traffic not yet written, by user and period
+    pending: HashMap<(String, String), u64>,
+    /// Modification time of the file when it was last read or written
+    mtime: Option<SystemTime>,
+}
+
+pub struct Quotas {
+    conf: QuotaConfig,
+    tracked: Mutex<Tracked>,
+    /// Held through a save, which does its file I/O without `tracked`
+    saving: Mutex<()>,
+}
+
+impl Quotas {
+    pub fn from_cfg(conf: QuotaConfig) -> Result<Self, Error> {
+        let mtime = mtime(&conf.state_file);
+        let state = QuotaState::read(&conf.state_file)?;
+        Ok(Quotas {
+            conf,
+            tracked: Mutex::new(Tracked {
+                state,
+                pending: HashMap::new(),
+                mtime,
+            }),
+            saving: Mutex::new(()),
+        })
+    }
+
+    fn current_period(&self) -> String {
+        period_of(self.conf.period, SystemTime::now())
+    }
+
+    /// Turns a user away whose quota is used up. Clients that did not
+    /// authenticate have no quota.
+    pub fn check(&self, user: Option<&str>) -> Result<(), OutgoingError> {
+        let user = match user {
+            Some(user) => user,
+            None => return Ok(()),
+        };
+        let limit = match self.conf.limit(user) {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+        let used = self
+            .tracked
+            .lock()
+            .unwrap()
+            .state
+            .used(user, &self.current_period());
+        if used >= limit {
+            Err(OutgoingError::ConnectionNotAllowed(
+                Error::from_description(&format!(
+                    "quota of user {} used up ({} of {} bytes)",
+                    user, used, limit
+                )),
+            ))?
+        }
+        Ok(())
+    }
+
+    /// Counts traffic of `user`
+    pub fn add(&self, user: Option<&str>, bytes: u64) {
+        let user = match user {
+            Some(user) if self.conf.limit(user).is_some() => user,
+            _ => return,
+        };
+        let period = self.current_period();
+        let mut tracked = self.tracked.lock().unwrap();
+        tracked.state.add(user, &period, bytes);
+        *tracked
+            .pending
+            .entry((user.to_string(), period))
+            .or_insert(0) += bytes;
+    }
+
+    /// Writes the usage to the state file, after taking in changes that
+    /// were made to the file by others. Blocks on file I/O, but counting
+    /// and checks can go on meanwhile.
+    pub fn save(&self) -> Result<(), Error> {
+        let _saving = self.saving.lock().unwrap();
+        let path = &self.conf.state_file;
+        let known = self.tracked.lock().unwrap().mtime;
+        let changed = mtime(path) != known;
+        let from_file = if changed {
+            info!("{} changed, reading it back", path);
+            Some(QuotaState::read(path)?)
+        } else {
+            None
+        };
+        let (state, written) = {
+            let mut tracked = self.tracked.lock().unwrap();
+            if let Some(mut state) = from_file {
+                for ((user, period), bytes) in &tracked.pending {
+                    state.add(user, period, *bytes);
+                }
+                tracked.state = state;
+            }
+            if !changed && tracked.pending.is_empty() {
+                return Ok(());
+            }
+            (tracked.state.clone(), std::mem::take(&mut tracked.pending))
+        };
+        let result = state.write(path);
+        let mut tracked = self.tracked.lock().unwrap();
+        match result {
+            Ok(()) => tracked.mtime = mtime(path),
+            // Still to be written
+            Err(_) => {
+                for (key, bytes) in written {
+                    *tracked.pending.entry(key).or_insert(0) += bytes;
+                }
+            }
+        }
+        result
+    }
+
+    /// `save` on a blocking thread
+    pub async fn save_async(self: &Arc<Self>) -> Result<(), Error> {
+        let quotas = self.clone();
+        tokio::task::spawn_blocking(move || quotas.save())
+            .await
+            .map_err(|e| Error::from_description(&e.to_string()))?
+    }
+
+    /// Saves every `save_interval`
+    pub async fn run(self: Arc<Self>) {
+        let mut ticker = tokio::time::interval(Duration::from_secs(self.conf.save_interval.max(1)));
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            if let Err(e) = self.save_async().await {
+                error!("can't save quota state: {}", e);
+            }
+        }
+    }
+}
+
+impl Subscriber for Quotas {
+    fn transferred(&self, user: Option<&str>, bytes: u64) {
+        self.add(user, bytes);
+    }
+}
+
+/// `rocks quota show`
+pub fn show(conf: &QuotaConfig) -> Result<(), Error> {
+    let state = QuotaState::read(&conf.state_file)?;
+    let period = period_of(conf.period, SystemTime::now());
+    let mut users = state.users.keys().cloned().collect::<Vec<_>>();
+    users.extend(
+        conf.users
+            .keys()
+            .filter(|u| !state.users.contains_key(*u))
+            .cloned(),
+    );
+    users.sort();
+    println!("period {}", period);
+    for user in users {
+        let limit = conf
+            .limit(&user)
+            .map_or("unlimited".to_string(), |l| l.to_string());
+        println!(
+            "{}: {} of {} bytes",
+            user,
+            state.used(&user, &period),
+            limit
+        );
+    }
+    Ok(())
+}
+
+/// `rocks quota reset [user]`; a running server picks the change up when it
+/// next saves.
+pub fn reset(conf: &QuotaConfig, user: Option<&str>) -> Result<(), Error> {
+    let mut state = QuotaState::read(&conf.state_file)?;
+    match user {
+        Some(user) => {
+            if state.users.remove(user).is_none() {
+                println!("{} has no recorded usage", user);
+                return Ok(());
+            }
+        }
+        None => state.users.clear(),
+    }
+    state.write(&conf.state_file)?;
+    println!("usage reset for {}", user.unwrap_or("all users"));
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_quota {
+    use super::*;
+
+    #[test]
+    fn periods() {
+        let at = |days: u64| UNIX_EPOCH + Duration::from_secs(days * 86400 + 3600);
+        assert_eq!(period_of(QuotaPeriod::Daily, at(0)), "1970-01-01");
+        assert_eq!(period_of(QuotaPeriod::Daily, at(19723)), "2024-01-01");
+        assert_eq!(period_of(QuotaPeriod::Daily, at(19782)), "2024-02-29");
+        assert_eq!(period_of(QuotaPeriod::Monthly, at(19783)), "2024-03");
+    }
+
+    fn config(path: &str) -> QuotaConfig {
+        QuotaConfig {
+            state_file: path.to_string(),
+            period: QuotaPeriod::Monthly,
+            per_user: Some(100),
+            users: HashMap::from([("bob".to_string(), 1000)]),
+            save_interval: 60,
+        }
+    }
+
+    #[test]
+    fn enforce_and_merge_resets() {
+        let path = std::env::temp_dir().join(format!("rocks-quota-{}.toml", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+        let conf = config(path);
+
+        let quotas = Quotas::from_cfg(conf.clone()).unwrap();
+        quotas.add(Some("alice"), 60);
+        quotas.add(Some("bob"), 600);
+        quotas.add(None, 1_000_000);
+        assert!(quotas.check(Some("alice")).is_ok());
+        quotas.add(Some("alice"), 40);
+        assert!(quotas.check(Some("alice")).is_err());
+        assert!(quotas.check(Some("bob")).is_ok());
+        assert!(quotas.check(None).is_ok());
+        quotas.save().unwrap();
+
+        // Survives a restart
+        let quotas = Quotas::from_cfg(conf.clone()).unwrap();
+        assert!(quotas.check(Some("alice")).is_err());
+
+        // A reset from the command line, while traffic goes on
+        quotas.add(Some("bob"), 100);
+        std::thread::sleep(Duration::from_millis(20));
+        reset(&conf, Some("alice")).unwrap();
+        quotas.save().unwrap();
+        assert!(quotas.check(Some("alice")).is_ok());
+        let period = period_of(QuotaPeriod::Monthly, SystemTime::now());
+        let state = QuotaState::read(path).unwrap();
+        assert_eq!(state.used("alice", &period), 0);
+        assert_eq!(state.used("bob", &period), 700);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn concurrent_writes() {
+        let name = format!("rocks-quota-writes-{}.toml", std::process::id());
+        let path = std::env::temp_dir().join(&name);
+        let path = path.to_str().unwrap().to_string();
+        let writers = (0..4)
+            .map(|i| {
+                let path = path.clone();
+                std::thread::spawn(move || {
+                    let mut state = QuotaState::default();
+                    state.add(&format!("user{}", i), "2024-03", 1);
+                    for _ in 0..50 {
+                        state.write(&path).unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        // One of them, whole
+        assert_eq!(QuotaState::read(&path).unwrap().users.len(), 1);
+        std::fs::remove_file(&path).unwrap();
+        let leftovers = std::fs::read_dir(std::env::temp_dir())
+            .unwrap()
+            .filter(|e| {
+                e.as_ref()
+                    .unwrap()
+                    .file_name()
+                    .to_string_lossy()
+                    .starts_with(&name)
+            })
+            .count();
+        assert_eq!(leftovers, 0);
+    }
+}
@@ -0,0 +1,172 @@
+//! Bandwidth limits: token buckets for the whole server, an incoming, and
+//! each authenticated user, separately for upload and download.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::config::{RateLimitConfig, RateLimitsConfig};
+
+/// Lets `rate` bytes per second through, after a burst of up to one
+/// second's worth.
+///
+/// Takers queue on a fair lock and the one at the front sleeps off its debt
+/// while holding it, so connections sharing a bucket take turns chunk by
+/// chunk.
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    /// Tokens and when they were counted; negative while in debt
+    state: tokio::sync::Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64) -> Self {
+        TokenBucket {
+            rate: rate as f64,
+            state: tokio::sync::Mutex::new((rate as f64, Instant::now())),
+        }
+    }
+
+    /// Takes `n` bytes worth of tokens, waiting until the bucket is out of
+    /// debt again.
+    pub async fn take(&self, n: usize) {
+        let mut state = self.state.lock().await;
+        let (tokens, counted) = &mut *state;
+        let now = Instant::now();
+        *tokens = (*tokens + now.duration_since(*counted).as_secs_f64() * self.rate).min(self.rate);
+        *counted = now;
+        *tokens -= n as f64;
+        if *tokens < 0.0 {
+            tokio::time::sleep(Duration::from_secs_f64(-*tokens / self.rate)).await;
+        }
+    }
+}
+
+/// The upload and download buckets of one scope
+#[derive(Debug)]
+struct Buckets {
+    upload: Option<Arc<TokenBucket>>,
+    download: Option<Arc<TokenBucket>>,
+}
+
+impl Buckets {
+    fn from_cfg(conf: &RateLimitConfig) -> Self {
+        let bucket = |rate: Option<u64>| {
+            rate.filter(|&r| r > 0)
+                .map(|r| Arc::new(TokenBucket::new(r)))
+        };
+        Buckets {
+            upload: bucket(conf.upload),
+            download: bucket(conf.download),
+        }
+    }
+    fn add_to(&self, limits: &mut Limits) {
+        limits.up.extend(self.upload.clone());
+        limits.down.extend(self.download.clone());
+    }
+}
+
+/// The buckets a connection draws from
+#[derive(Debug, Default, Clone)]
+pub struct Limits {
+    pub up: Vec<Arc<TokenBucket>>,
+    pub down: Vec<Arc<TokenBucket>>,
+}
+
+/// The limits of an incoming
+#[derive(Debug)]
+pub struct IncomingLimit(Buckets);
+
+impl IncomingLimit {
+    pub fn from_cfg(conf: &RateLimitConfig) -> Self {
+        IncomingLimit(Buckets::from_cfg(conf))
+    }
+}
+
+/// The global limits, and the buckets of every user seen so far
+#[derive(Debug)]
+pub struct RateLimiter {
+    global: Buckets,
+    per_user: RateLimitConfig,
+    users: HashMap<String, RateLimitConfig>,
+    user_buckets: Mutex<HashMap<String, Arc<Buckets>>>,
+}
+
+impl RateLimiter {
+    pub fn from_cfg(conf: &RateLimitsConfig) -> Self {
+        RateLimiter {
+            global: Buckets::from_cfg(&conf.global()),
+            per_user: conf.per_user.clone(),
+            users: conf.users.clone(),
+            user_buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The buckets for a connection of `user` through `incoming`. All
+    /// connections of a user share the user's buckets.
+    pub fn limits(&self, incoming: &IncomingLimit, user: Option<&str>) -> Limits {
+        let mut limits = Limits::default();
+        self.global.add_to(&mut limits);
+        incoming.0.add_to(&mut limits);
+        if let Some(user) = user {
+            let conf = self.users.get(user).unwrap_or(&self.per_user);
+            if conf.upload.is_some() || conf.download.is_some() {
+                self.user_buckets
+                    .lock()
+                    .unwrap()
+                    .entry(user.to_string())
+                    .or_insert_with(|| Arc::new(Buckets::from_cfg(conf)))
+                    .add_to(&mut limits);
+            }
+        }
+        limits
+    }
+}
+
+#[cfg(test)]
+mod test_ratelimit {
+    use super::*;
+
+    #[tokio::test]
+    async fn bucket_paces_after_burst() {
+        let bucket = TokenBucket::new(1_000_000);
+        let start = Instant::now();
+        bucket.take(1_000_000).await;
+        assert!(start.elapsed() < Duration::from_millis(50));
+        bucket.take(200_000).await;
+        assert!(start.elapsed() >= Duration::from_millis(190));
+    }
+
+    #[test]
+    fn users_share_buckets() {
+        let limiter = RateLimiter::from_cfg(&RateLimitsConfig {
+            download: Some(1000),
+            per_user: RateLimitConfig {
+                upload: Some(100),
+                download: None,
+            },
+            users: HashMap::from([(
+                "bob".to_string(),
+                RateLimitConfig {
+                    upload: None,
+                    download: Some(0),
+                },
+            )]),
+            ..Default::default()
+        });
+        let incoming = IncomingLimit::from_cfg(&RateLimitConfig {
+            upload: None,
+            download: Some(500),
+        });
+        let a = limiter.limits(&incoming, Some("alice"));
+        let b = limiter.limits(&incoming, Some("alice"));
+        assert_eq!((a.up.len(), a.down.len()), (1, 2));
+        assert!(Arc::ptr_eq(&a.up[0], &b.up[0]));
+        // Global and incoming buckets only
+        let bob = limiter.limits(&incoming, Some("bob"));
+        assert_eq!((bob.up.len(), bob.down.len()), (0, 2));
+        assert!(Arc::ptr_eq(&a.down[0], &bob.down[0]));
+        assert_eq!(limiter.limits(&incoming, None).down.len(), 2);
+    }
+}
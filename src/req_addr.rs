@@ -1,4 +1,4 @@
-use std::net::{SocketAddr, ToSocketAddrs};
+use std::net::SocketAddr;
 
 use log::debug;
 
@@ -6,7 +6,7 @@ use crate::error::Error;
 // use trust_dns_resolver::AsyncResolver;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
-#[derive(Clone, Debug, serde_derive::Serialize, serde_derive::Deserialize)]
+#[derive(Clone, Debug, PartialEq, Eq, serde_derive::Serialize, serde_derive::Deserialize)]
 pub enum ReqAddr {
     IP(SocketAddr),
     Domain(String, u16),
@@ -26,12 +26,10 @@ impl ReqAddr {
     }
     pub fn parse_address_v4(addr_bytes: &[u8]) -> Result<ReqAddr, Error> {
         if addr_bytes.len() != 6 {
-            if addr_bytes.len() != 6 {
-                Err(Error::from_description("IPv4 address format error"))?
-            }
+            Err(Error::from_description("IPv4 address format error"))?
         }
         let host = Ipv4Addr::new(addr_bytes[0], addr_bytes[1], addr_bytes[2], addr_bytes[3]);
-        let port = ((addr_bytes[3] as u16) << 8) | (addr_bytes[4] as u16);
+        let port = ((addr_bytes[4] as u16) << 8) | (addr_bytes[5] as u16);
         Ok(ReqAddr::from_addr(SocketAddr::new(IpAddr::V4(host), port)))
     }
     pub fn parse_address_v6(addr_bytes: &[u8]) -> Result<ReqAddr, Error> {
@@ -51,23 +49,26 @@ impl ReqAddr {
         Ok(ReqAddr::from_addr(SocketAddr::new(IpAddr::V6(host), port)))
     }
     pub fn parse_domain(addr_len: usize, addr_bytes: &[u8]) -> Result<ReqAddr, Error> {
+        if addr_len == 0 || addr_bytes.len() != addr_len + 2 {
+            Err(Error::from_description("domain address format error"))?
+        }
         let hostname = std::str::from_utf8(&addr_bytes[0..addr_len])?.to_string();
         let port = ((addr_bytes[addr_len] as u16) << 8) | (addr_bytes[addr_len + 1] as u16);
         Ok(ReqAddr::from_domain(hostname, port))
     }
 
-    //     #[allow(dead_code)]
-    pub fn resolve_local(&self) -> Result<SocketAddr, Error> {
+    pub async fn resolve_local(&self) -> Result<Vec<SocketAddr>, Error> {
         match self {
-            ReqAddr::IP(ip) => Ok(ip.clone()),
+            ReqAddr::IP(ip) => Ok(vec![*ip]),
             ReqAddr::Domain(domain, port) => {
-                let sas = (domain.as_ref(), *port)
-                    .to_socket_addrs()?
-                    .into_iter()
-                    .next();
-                debug!("sas: {:?}", sas);
-                sas.map(|addr| SocketAddr::new(addr.ip(), *port))
-                    .ok_or(Error::from_description("Local resolve faiure"))
+                let addrs = tokio::net::lookup_host((domain.as_str(), *port))
+                    .await?
+                    .collect::<Vec<_>>();
+                debug!("sas: {:?}", addrs);
+                if addrs.is_empty() {
+                    Err(Error::from_description("Local resolve faiure"))?
+                }
+                Ok(addrs)
             }
         }
     }
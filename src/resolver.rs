@@ -0,0 +1,327 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use log::{debug, warn};
+
+use crate::config::HostsConfig;
+use crate::error::Error;
+use crate::req_addr::ReqAddr;
+use crate::rules::domain_has_suffix;
+
+/// Longest CNAME-style chain followed inside the hosts table
+const MAX_ALIAS_DEPTH: usize = 8;
+
+/// Reverse lookup through the system resolver
+#[cfg(unix)]
+fn getnameinfo(ip: IpAddr) -> Result<String, Error> {
+    let addr = socket2::SockAddr::from(SocketAddr::new(ip, 0));
+    let mut host = [0 as libc::c_char; 1025];
+    let r = unsafe {
+        libc::getnameinfo(
+            addr.as_ptr() as *const libc::sockaddr,
+            addr.len(),
+            host.as_mut_ptr(),
+            host.len() as libc::socklen_t,
+            std::ptr::null_mut(),
+            0,
+            libc::NI_NAMEREQD,
+        )
+    };
+    if r != 0 {
+        Err(Error::from_description(&format!("no name for {}", ip)))?
+    }
+    let name = unsafe { std::ffi::CStr::from_ptr(host.as_ptr()) };
+    Ok(name.to_str()?.to_string())
+}
+#[cfg(not(unix))]
+fn getnameinfo(_ip: IpAddr) -> Result<String, Error> {
+    Err(Error::from_description("reverse lookup is not supported"))
+}
+
+#[derive(Debug, Clone)]
+enum HostTarget {
+    Ips(Vec<IpAddr>),
+    Alias(String),
+}
+
+impl HostTarget {
+    fn parse(s: &str) -> Self {
+        match s.parse::<IpAddr>() {
+            Ok(ip) => HostTarget::Ips(vec![ip]),
+            Err(_) => HostTarget::Alias(s.trim_end_matches('.').to_ascii_lowercase()),
+        }
+    }
+}
+
+/// Static overrides from the `[hosts]` section and an optional hosts-format
+/// file. Entries starting with `*.` match any subdomain.
+#[derive(Debug, Default)]
+struct Hosts {
+    exact: HashMap<String, HostTarget>,
+    // (suffix, target), longest suffix first
+    wildcard: Vec<(String, HostTarget)>,
+}
+
+impl Hosts {
+    /// With `merge`, addresses are added to those already known for the
+    /// name (as several hosts file lines do); otherwise the entry replaces
+    /// what was there.
+    fn insert(&mut self, name: &str, target: HostTarget, merge: bool) {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        let existing = match name.strip_prefix("*.") {
+            Some(suffix) => match self.wildcard.iter().position(|(s, _)| s == suffix) {
+                Some(i) => &mut self.wildcard[i].1,
+                None => {
+                    self.wildcard.push((suffix.to_string(), target));
+                    return;
+                }
+            },
+            None => self.exact.entry(name).or_insert(HostTarget::Ips(vec![])),
+        };
+        match (existing, target) {
+            (HostTarget::Ips(ips), HostTarget::Ips(more)) if merge => ips.extend(more),
+            (existing, target) => *existing = target,
+        }
+    }
+
+    /// Reads `/etc/hosts` style lines: an address followed by names
+    fn read_file(&mut self, path: &str) -> Result<(), Error> {
+        let content = std::fs::read_to_string(path)?;
+        for (n, line) in content.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default();
+            let mut fields = line.split_whitespace();
+            let ip = match fields.next().map(|ip| (ip, ip.parse::<IpAddr>())) {
+                Some((_, Ok(ip))) => ip,
+                // Such as scoped addresses (fe80::1%lo0)
+                Some((ip, Err(_))) => {
+                    warn!("{} line {}: skipping unusable address {}", path, n + 1, ip);
+                    continue;
+                }
+                None => continue,
+            };
+            for name in fields {
+                self.insert(name, HostTarget::Ips(vec![ip]), true);
+            }
+        }
+        Ok(())
+    }
+
+    fn lookup(&self, domain: &str) -> Option<&HostTarget> {
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        self.exact.get(&domain).or_else(|| {
+            self.wildcard
+                .iter()
+                .find(|(suffix, _)| {
+                    domain.len() > suffix.len() && domain_has_suffix(&domain, suffix)
+                })
+                .map(|(_, target)| target)
+        })
+    }
+}
+
+/// Outcome of looking a domain up in the hosts table
+#[derive(Debug)]
+pub enum StaticAnswer {
+    /// The domain is pinned to these addresses
+    Addrs(Vec<IpAddr>),
+    /// Not pinned; this name (possibly an alias target) needs a real lookup
+    Name(String),
+}
+
+/// Resolves request addresses, consulting the static hosts table before the
+/// system resolver.
+#[derive(Debug, Default)]
+pub struct Resolver {
+    hosts: Hosts,
+    /// Domains looked up so far
+    lookups: AtomicU64,
+}
+
+impl Resolver {
+    pub fn from_cfg(conf: Option<&HostsConfig>) -> Result<Self, Error> {
+        let mut hosts = Hosts::default();
+        if let Some(conf) = conf {
+            if let Some(file) = &conf.file {
+                hosts.read_file(file)?;
+            }
+            for (name, target) in &conf.entries {
+                hosts.insert(name, HostTarget::parse(target), false);
+            }
+        }
+        hosts
+            .wildcard
+            .sort_by_key(|(suffix, _)| std::cmp::Reverse(suffix.len()));
+        let resolver = Resolver {
+            hosts,
+            lookups: AtomicU64::new(0),
+        };
+        // Every lookup ends up following one of these chains
+        let aliases = resolver.hosts.exact.values();
+        for target in aliases.chain(resolver.hosts.wildcard.iter().map(|(_, t)| t)) {
+            if let HostTarget::Alias(alias) = target {
+                if resolver.follow_aliases(alias).is_none() {
+                    Err(Error::from_description(&format!(
+                        "hosts alias {} is part of a loop or a chain longer than {}",
+                        alias, MAX_ALIAS_DEPTH
+                    )))?
+                }
+            }
+        }
+        Ok(resolver)
+    }
+
+    /// Follows aliases from `domain`; `None` when they go on for longer
+    /// than `MAX_ALIAS_DEPTH`
+    fn follow_aliases(&self, domain: &str) -> Option<StaticAnswer> {
+        let mut name = domain.to_string();
+        for _ in 0..MAX_ALIAS_DEPTH {
+            match self.hosts.lookup(&name) {
+                Some(HostTarget::Ips(ips)) => return Some(StaticAnswer::Addrs(ips.clone())),
+                Some(HostTarget::Alias(alias)) => name = alias.clone(),
+                None => return Some(StaticAnswer::Name(name)),
+            }
+        }
+        None
+    }
+
+    pub fn lookup_static(&self, domain: &str) -> StaticAnswer {
+        match self.hosts.lookup(domain) {
+            Some(HostTarget::Ips(ips)) => StaticAnswer::Addrs(ips.clone()),
+            // Every alias chain was checked in from_cfg
+            Some(HostTarget::Alias(alias)) => self
+                .follow_aliases(alias)
+                .unwrap_or_else(|| StaticAnswer::Name(alias.clone())),
+            None => StaticAnswer::Name(domain.to_string()),
+        }
+    }
+
+    pub async fn resolve(&self, req: &ReqAddr) -> Result<Vec<SocketAddr>, Error> {
+        let (domain, port) = match req {
+            ReqAddr::IP(addr) => return Ok(vec![*addr]),
+            ReqAddr::Domain(domain, port) => (domain, *port),
+        };
+        self.lookups.fetch_add(1, Ordering::Relaxed);
+        match self.lookup_static(domain) {
+            StaticAnswer::Addrs(ips) => {
+                debug!("{} found in hosts: {:?}", domain, ips);
+                Ok(ips
+                    .into_iter()
+                    .map(|ip| SocketAddr::new(ip, port))
+                    .collect())
+            }
+            StaticAnswer::Name(name) => ReqAddr::Domain(name, port).resolve_local().await,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn lookups(&self) -> u64 {
+        self.lookups.load(Ordering::Relaxed)
+    }
+
+    pub async fn reverse(&self, ip: IpAddr) -> Result<String, Error> {
+        tokio::task::spawn_blocking(move || getnameinfo(ip))
+            .await
+            .map_err(|e| Error::from_description(&e.to_string()))?
+    }
+}
+
+#[cfg(test)]
+mod test_resolver {
+    use super::*;
+
+    fn resolver(entries: &[(&str, &str)], file: Option<&str>) -> Result<Resolver, Error> {
+        Resolver::from_cfg(Some(&HostsConfig {
+            file: file.map(str::to_string),
+            entries: entries
+                .iter()
+                .map(|(name, target)| (name.to_string(), target.to_string()))
+                .collect(),
+        }))
+    }
+
+    fn addrs(answer: StaticAnswer) -> Vec<String> {
+        match answer {
+            StaticAnswer::Addrs(ips) => ips.iter().map(|ip| ip.to_string()).collect(),
+            StaticAnswer::Name(name) => panic!("not pinned, looks up {}", name),
+        }
+    }
+
+    fn name(answer: StaticAnswer) -> String {
+        match answer {
+            StaticAnswer::Name(name) => name,
+            StaticAnswer::Addrs(ips) => panic!("pinned to {:?}", ips),
+        }
+    }
+
+    #[test]
+    fn entries_and_aliases() {
+        let r = resolver(
+            &[
+                ("api.internal.example", "10.1.2.3"),
+                ("*.staging.example", "api.internal.example"),
+                ("*.deep.staging.example", "10.9.9.9"),
+                ("www.example", "Example.ORG."),
+            ],
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            addrs(r.lookup_static("API.internal.example.")),
+            ["10.1.2.3"]
+        );
+        assert_eq!(addrs(r.lookup_static("a.staging.example")), ["10.1.2.3"]);
+        assert_eq!(
+            addrs(r.lookup_static("a.deep.staging.example")),
+            ["10.9.9.9"]
+        );
+        // The wildcard only covers subdomains
+        assert_eq!(name(r.lookup_static("staging.example")), "staging.example");
+        assert_eq!(name(r.lookup_static("www.example")), "example.org");
+    }
+
+    #[test]
+    fn alias_loops() {
+        assert!(resolver(
+            &[("a.example", "b.example"), ("b.example", "a.example")],
+            None
+        )
+        .is_err());
+        assert!(resolver(&[("*.example", "www.example")], None).is_err());
+        let chain = (0..MAX_ALIAS_DEPTH + 1)
+            .map(|i| (format!("{}.example", i), format!("{}.example", i + 1)))
+            .collect::<Vec<_>>();
+        let chain = chain
+            .iter()
+            .map(|(a, b)| (a.as_str(), b.as_str()))
+            .collect::<Vec<_>>();
+        assert!(resolver(&chain, None).is_err());
+        let r = resolver(&chain[1..], None).unwrap();
+        assert_eq!(
+            name(r.lookup_static("1.example")),
+            format!("{}.example", MAX_ALIAS_DEPTH + 1)
+        );
+    }
+
+    #[test]
+    fn hosts_file() {
+        let path = std::env::temp_dir().join(format!("rocks-hosts-{}", std::process::id()));
+        std::fs::write(
+            &path,
+            "# comment\n\
+             127.0.0.1 localhost box\n\
+             ::1 localhost\n\
+             fe80::1%lo0 localhost\n\
+             not-an-address box\n\
+             \n\
+             192.0.2.1 box # trailing\n",
+        )
+        .unwrap();
+        let r = resolver(&[("box", "192.0.2.9")], path.to_str());
+        std::fs::remove_file(&path).unwrap();
+        let r = r.unwrap();
+        assert_eq!(addrs(r.lookup_static("localhost")), ["127.0.0.1", "::1"]);
+        // Config entries replace what the file says
+        assert_eq!(addrs(r.lookup_static("box")), ["192.0.2.9"]);
+    }
+}
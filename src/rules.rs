@@ -0,0 +1,530 @@
+use std::net::IpAddr;
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+
+use ipnet::IpNet;
+use log::{debug, error};
+use regex::Regex;
+
+use crate::config::{GeoIpConfig, OutgoingConfig, RuleConfig};
+use crate::error::Error;
+use crate::geoip::GeoIp;
+use crate::outgoing::{check_implemented, OutgoingEntry};
+use crate::req_addr::ReqAddr;
+use crate::resolver::Resolver;
+use crate::stats::Stats;
+
+pub(crate) fn parse_cidr(s: &str) -> Result<IpNet, Error> {
+    match s.parse::<IpNet>() {
+        Ok(net) => Ok(net.trunc()),
+        Err(_) => Ok(IpNet::from(s.parse::<IpAddr>()?)),
+    }
+}
+
+pub(crate) fn parse_port_range(s: &str) -> Result<RangeInclusive<u16>, Error> {
+    let parse = |p: &str| {
+        p.trim()
+            .parse::<u16>()
+            .map_err(|_| Error::from_description(&format!("invalid port: {}", s)))
+    };
+    match s.split_once('-') {
+        Some((from, to)) => {
+            let (from, to) = (parse(from)?, parse(to)?);
+            if from > to {
+                Err(Error::from_description(&format!("empty port range: {}", s)))?
+            }
+            Ok(from..=to)
+        }
+        None => {
+            let p = parse(s)?;
+            Ok(p..=p)
+        }
+    }
+}
+
+pub(crate) fn domain_has_suffix(domain: &str, suffix: &str) -> bool {
+    let domain = domain.trim_end_matches('.');
+    let suffix = suffix.trim_start_matches('.');
+    if domain.len() < suffix.len() {
+        return false;
+    }
+    let (head, tail) = domain.split_at(domain.len() - suffix.len());
+    tail.eq_ignore_ascii_case(suffix) && (head.is_empty() || head.ends_with('.'))
+}
+
+struct Rule {
+    domain_suffix: Vec<String>,
+    domain_regex: Vec<Regex>,
+    ip_cidr: Vec<IpNet>,
+    port: Vec<RangeInclusive<u16>>,
+    user: Vec<String>,
+    source_cidr: Vec<IpNet>,
+    geoip_country: Vec<String>,
+    geoip_asn: Vec<u32>,
+    outgoing: usize,
+}
+
+impl Rule {
+    fn from_cfg(
+        cfg: &RuleConfig,
+        outgoings: &[OutgoingConfig],
+        geoip: Option<&GeoIp>,
+    ) -> Result<Self, Error> {
+        if !cfg.geoip_country.is_empty() && !geoip.is_some_and(GeoIp::has_country) {
+            Err(Error::from_description(
+                "geoip_country rule requires geoip.country_db",
+            ))?
+        }
+        if !cfg.geoip_asn.is_empty() && !geoip.is_some_and(GeoIp::has_asn) {
+            Err(Error::from_description(
+                "geoip_asn rule requires geoip.asn_db",
+            ))?
+        }
+        let outgoing = outgoings
+            .iter()
+            .position(|o| o.name == cfg.outgoing)
+            .ok_or_else(|| {
+                Error::from_description(&format!(
+                    "rule refers to unknown outgoing {}",
+                    cfg.outgoing
+                ))
+            })?;
+        check_implemented(&outgoings[outgoing])?;
+        Ok(Rule {
+            domain_suffix: cfg.domain_suffix.clone(),
+            domain_regex: cfg
+                .domain_regex
+                .iter()
+                .map(|r| Regex::new(r))
+                .collect::<Result<_, _>>()?,
+            ip_cidr: cfg
+                .ip_cidr
+                .iter()
+                .map(|c| parse_cidr(c))
+                .collect::<Result<_, _>>()?,
+            port: cfg
+                .port
+                .iter()
+                .map(|p| parse_port_range(p))
+                .collect::<Result<_, _>>()?,
+            user: cfg.user.clone(),
+            source_cidr: cfg
+                .source_cidr
+                .iter()
+                .map(|c| parse_cidr(c))
+                .collect::<Result<_, _>>()?,
+            geoip_country: cfg
+                .geoip_country
+                .iter()
+                .map(|c| c.to_ascii_uppercase())
+                .collect(),
+            geoip_asn: cfg.geoip_asn.clone(),
+            outgoing,
+        })
+    }
+
+    fn needs_geoip(&self) -> bool {
+        !self.geoip_country.is_empty() || !self.geoip_asn.is_empty()
+    }
+
+    /// The conditions that can be checked without looking anything up
+    fn matches_request(&self, req: &ReqAddr, user: Option<&str>, source: Option<IpAddr>) -> bool {
+        let domain = match req {
+            ReqAddr::Domain(domain, _) => Some(domain.as_str()),
+            ReqAddr::IP(_) => None,
+        };
+        let ip = match req {
+            ReqAddr::IP(addr) => Some(addr.ip()),
+            ReqAddr::Domain(..) => None,
+        };
+        (self.domain_suffix.is_empty()
+            || domain.is_some_and(|d| self.domain_suffix.iter().any(|s| domain_has_suffix(d, s))))
+            && (self.domain_regex.is_empty()
+                || domain.is_some_and(|d| self.domain_regex.iter().any(|r| r.is_match(d))))
+            && (self.ip_cidr.is_empty()
+                || ip.is_some_and(|ip| self.ip_cidr.iter().any(|n| n.contains(&ip))))
+            && (self.port.is_empty() || self.port.iter().any(|r| r.contains(&req.port())))
+            && (self.user.is_empty() || user.is_some_and(|u| self.user.iter().any(|n| n == u)))
+            && (self.source_cidr.is_empty()
+                || source.is_some_and(|ip| self.source_cidr.iter().any(|n| n.contains(&ip))))
+    }
+
+    /// `dest_ip` is the request IP, or the resolved one for domain requests
+    fn matches_geoip(&self, dest_ip: Option<IpAddr>, geoip: Option<&GeoIp>) -> bool {
+        (self.geoip_country.is_empty()
+            || dest_ip
+                .and_then(|ip| geoip?.country(ip))
+                .is_some_and(|c| self.geoip_country.contains(&c)))
+            && (self.geoip_asn.is_empty()
+                || dest_ip
+                    .and_then(|ip| geoip?.asn(ip))
+                    .is_some_and(|a| self.geoip_asn.contains(&a)))
+    }
+}
+
+/// Picks one of the configured outgoings for each request, by evaluating the
+/// `[[rule]]` table in order. Requests that match no rule use the first
+/// outgoing.
+pub struct Router {
+    outgoings: Vec<Arc<OutgoingEntry>>,
+    rules: Vec<Rule>,
+    geoip: Option<Arc<GeoIp>>,
+    resolver: Arc<Resolver>,
+}
+
+impl Router {
+    pub fn from_cfg(
+        outgoings: Vec<OutgoingConfig>,
+        rules: &[RuleConfig],
+        geoip: Option<&GeoIpConfig>,
+        resolver: Arc<Resolver>,
+        stats: Arc<Stats>,
+    ) -> Result<Self, Error> {
+        if outgoings.is_empty() {
+            Err(Error::from_description("no outgoing configured"))?
+        }
+        for (i, o) in outgoings.iter().enumerate() {
+            if outgoings[..i].iter().any(|p| p.name == o.name) {
+                Err(Error::from_description(&format!(
+                    "duplicate outgoing name {}",
+                    o.name
+                )))?
+            }
+        }
+        // The first one takes what no rule matches
+        check_implemented(&outgoings[0])?;
+        let geoip = geoip.map(GeoIp::from_cfg).transpose()?.map(Arc::new);
+        let rules = rules
+            .iter()
+            .map(|r| Rule::from_cfg(r, &outgoings, geoip.as_deref()))
+            .collect::<Result<_, _>>()?;
+        let outgoings = outgoings
+            .into_iter()
+            .map(|o| OutgoingEntry::from_cfg(o, resolver.clone(), stats.clone()).map(Arc::new))
+            .collect::<Result<_, _>>()?;
+        Ok(Router {
+            outgoings,
+            rules,
+            geoip,
+            resolver,
+        })
+    }
+
+    pub fn outgoing(&self, name: &str) -> Option<&Arc<OutgoingEntry>> {
+        self.outgoings.iter().find(|o| o.conf.name == name)
+    }
+
+    /// The GeoIP databases, when there are any
+    pub fn geoip(&self) -> Option<Arc<GeoIp>> {
+        self.geoip.clone()
+    }
+
+    /// `source` is the client address, when the client has one
+    pub async fn route(
+        &self,
+        req: &ReqAddr,
+        user: Option<&str>,
+        source: Option<IpAddr>,
+    ) -> &Arc<OutgoingEntry> {
+        let mut dest_ip = match req {
+            ReqAddr::IP(addr) => Some(Some(addr.ip())),
+            ReqAddr::Domain(..) => None,
+        };
+        let mut idx = 0;
+        for rule in &self.rules {
+            if !rule.matches_request(req, user, source) {
+                continue;
+            }
+            // Domains are only resolved locally when a GeoIP condition asks
+            if rule.needs_geoip() && dest_ip.is_none() {
+                dest_ip = Some(match self.resolver.resolve(req).await {
+                    Ok(addrs) => Some(addrs[0].ip()),
+                    Err(e) => {
+                        error!("can't resolve {} for geoip: {}", req, e);
+                        None
+                    }
+                });
+            }
+            if rule.matches_geoip(dest_ip.flatten(), self.geoip.as_deref()) {
+                idx = rule.outgoing;
+                break;
+            }
+        }
+        debug!("route {} -> {}", req, self.outgoings[idx].conf.name);
+        &self.outgoings[idx]
+    }
+
+    /// Like `route`, without looking domains up: GeoIP conditions only
+    /// match IP requests. For requests that are lookups themselves, which
+    /// must not leak or come back to our own DNS server.
+    pub fn route_no_resolve(
+        &self,
+        req: &ReqAddr,
+        user: Option<&str>,
+        source: Option<IpAddr>,
+    ) -> &Arc<OutgoingEntry> {
+        let dest_ip = match req {
+            ReqAddr::IP(addr) => Some(addr.ip()),
+            ReqAddr::Domain(..) => None,
+        };
+        let idx = self
+            .rules
+            .iter()
+            .find(|rule| {
+                rule.matches_request(req, user, source)
+                    && rule.matches_geoip(dest_ip, self.geoip.as_deref())
+            })
+            .map_or(0, |rule| rule.outgoing);
+        debug!("route {} -> {}", req, self.outgoings[idx].conf.name);
+        &self.outgoings[idx]
+    }
+}
+
+#[cfg(test)]
+mod test_rules {
+    use super::*;
+
+    fn outgoing(name: &str) -> OutgoingConfig {
+        toml::from_str(&format!("name = \"{}\"\ntype = \"Direct\"", name)).unwrap()
+    }
+
+    fn router(rules: &str) -> Router {
+        #[derive(serde_derive::Deserialize)]
+        struct Rules {
+            rule: Vec<RuleConfig>,
+        }
+        let rules = toml::from_str::<Rules>(rules).unwrap().rule;
+        let outgoings = ["default", "a", "b", "c"].map(outgoing).to_vec();
+        Router::from_cfg(
+            outgoings,
+            &rules,
+            None,
+            Arc::new(Resolver::default()),
+            Arc::new(Stats::default()),
+        )
+        .unwrap()
+    }
+
+    fn domain(d: &str, port: u16) -> ReqAddr {
+        ReqAddr::Domain(d.to_string(), port)
+    }
+
+    fn ip(s: &str) -> ReqAddr {
+        ReqAddr::IP(s.parse().unwrap())
+    }
+
+    async fn route(router: &Router, req: &ReqAddr, user: Option<&str>) -> String {
+        let source = Some("192.0.2.7".parse().unwrap());
+        router.route(req, user, source).await.conf.name.clone()
+    }
+
+    #[test]
+    fn port_ranges() {
+        assert_eq!(parse_port_range("443").unwrap(), 443..=443);
+        assert_eq!(parse_port_range("8000 - 8100").unwrap(), 8000..=8100);
+        assert!(parse_port_range("8100-8000").is_err());
+        assert!(parse_port_range("65536").is_err());
+        assert!(parse_port_range("http").is_err());
+    }
+
+    #[test]
+    fn domain_suffixes() {
+        assert!(domain_has_suffix("example.com", "example.com"));
+        assert!(domain_has_suffix("www.Example.com.", ".example.com"));
+        assert!(!domain_has_suffix("badexample.com", "example.com"));
+        assert!(!domain_has_suffix("com", "example.com"));
+    }
+
+    #[tokio::test]
+    async fn first_matching_rule_wins() {
+        let router = router(
+            r#"
+            [[rule]]
+            domain_suffix = ["lan"]
+            port = ["1-1023"]
+            outgoing = "a"
+
+            [[rule]]
+            domain_regex = ['^ads?\.']
+            outgoing = "b"
+
+            [[rule]]
+            ip_cidr = ["10.0.0.0/8", "fd00::/8"]
+            outgoing = "b"
+
+            [[rule]]
+            user = ["alice"]
+            source_cidr = ["192.0.2.0/24"]
+            outgoing = "c"
+            "#,
+        );
+        assert_eq!(route(&router, &domain("nas.lan", 80), None).await, "a");
+        // Both conditions of the first rule must match
+        assert_eq!(
+            route(&router, &domain("nas.lan", 8080), None).await,
+            "default"
+        );
+        assert_eq!(
+            route(&router, &domain("ad.example.com", 443), None).await,
+            "b"
+        );
+        assert_eq!(
+            route(&router, &domain("bad.example.com", 443), None).await,
+            "default"
+        );
+        assert_eq!(route(&router, &ip("10.1.2.3:22"), None).await, "b");
+        assert_eq!(route(&router, &ip("[fd00::1]:22"), None).await, "b");
+        // Domain conditions never match IP requests and the other way round
+        assert_eq!(
+            route(&router, &domain("10.1.2.3", 22), None).await,
+            "default"
+        );
+        assert_eq!(route(&router, &ip("11.1.2.3:22"), Some("alice")).await, "c");
+        assert_eq!(
+            route(&router, &ip("11.1.2.3:22"), Some("bob")).await,
+            "default"
+        );
+        // Without GeoIP rules, not resolving makes no difference
+        let no_resolve = |req: &ReqAddr| router.route_no_resolve(req, None, None).conf.name.clone();
+        assert_eq!(no_resolve(&domain("nas.lan", 53)), "a");
+        assert_eq!(no_resolve(&ip("10.1.2.3:53")), "b");
+        assert_eq!(no_resolve(&domain("example.com", 53)), "default");
+    }
+
+    #[tokio::test]
+    async fn geoip_rules() {
+        use crate::config::HostsConfig;
+        use crate::geoip::test_geoip::{temp_path, write_asn_db, write_country_db};
+
+        let (country_db, asn_db) = (temp_path("rules-country"), temp_path("rules-asn"));
+        write_country_db(&country_db, &[("192.0.2.0/24", "NZ")]);
+        write_asn_db(&asn_db, &[("198.51.100.0/24", 64500)]);
+        #[derive(serde_derive::Deserialize)]
+        struct Rules {
+            rule: Vec<RuleConfig>,
+        }
+        let rules = toml::from_str::<Rules>(
+            r#"
+            [[rule]]
+            domain_suffix = ["lan"]
+            outgoing = "a"
+
+            [[rule]]
+            geoip_country = ["NZ"]
+            outgoing = "b"
+
+            [[rule]]
+            geoip_asn = [64500]
+            outgoing = "c"
+            "#,
+        )
+        .unwrap()
+        .rule;
+        let resolver = Resolver::from_cfg(Some(&HostsConfig {
+            file: None,
+            entries: [("nz.example", "192.0.2.1"), ("as.example", "198.51.100.1")]
+                .iter()
+                .map(|(name, ip)| (name.to_string(), ip.to_string()))
+                .collect(),
+        }))
+        .unwrap();
+        let router = Router::from_cfg(
+            ["default", "a", "b", "c"].map(outgoing).to_vec(),
+            &rules,
+            Some(&GeoIpConfig {
+                country_db: Some(country_db.clone()),
+                asn_db: Some(asn_db.clone()),
+                reload_interval: 60,
+            }),
+            Arc::new(resolver),
+            Arc::new(Stats::default()),
+        )
+        .unwrap();
+        let lookups = || router.resolver.lookups();
+
+        assert_eq!(route(&router, &ip("192.0.2.9:443"), None).await, "b");
+        assert_eq!(route(&router, &ip("198.51.100.9:443"), None).await, "c");
+        assert_eq!(
+            route(&router, &ip("203.0.113.9:443"), None).await,
+            "default"
+        );
+        assert_eq!(lookups(), 0);
+        // Decided before a GeoIP rule is reached
+        assert_eq!(route(&router, &domain("nas.lan", 443), None).await, "a");
+        assert_eq!(lookups(), 0);
+        // Looked up once, for all the GeoIP rules
+        assert_eq!(route(&router, &domain("nz.example", 443), None).await, "b");
+        assert_eq!(lookups(), 1);
+        assert_eq!(route(&router, &domain("as.example", 443), None).await, "c");
+        assert_eq!(lookups(), 2);
+        // Lookups themselves are never resolved
+        let no_resolve = |req: &ReqAddr| router.route_no_resolve(req, None, None).conf.name.clone();
+        assert_eq!(no_resolve(&domain("nz.example", 53)), "default");
+        assert_eq!(no_resolve(&ip("192.0.2.9:53")), "b");
+        assert_eq!(lookups(), 2);
+        std::fs::remove_file(country_db).unwrap();
+        std::fs::remove_file(asn_db).unwrap();
+    }
+
+    #[test]
+    fn invalid_rules() {
+        let outgoings = vec![outgoing("default")];
+        let resolver = Arc::new(Resolver::default());
+        let stats = Arc::new(Stats::default());
+        let rule = |r: RuleConfig| {
+            Router::from_cfg(
+                outgoings.clone(),
+                &[r],
+                None,
+                resolver.clone(),
+                stats.clone(),
+            )
+            .is_err()
+        };
+        assert!(rule(RuleConfig {
+            outgoing: "missing".to_string(),
+            ..Default::default()
+        }));
+        assert!(rule(RuleConfig {
+            port: vec!["443-80".to_string()],
+            outgoing: "default".to_string(),
+            ..Default::default()
+        }));
+        assert!(rule(RuleConfig {
+            geoip_country: vec!["NZ".to_string()],
+            outgoing: "default".to_string(),
+            ..Default::default()
+        }));
+        assert!(Router::from_cfg(
+            vec![outgoing("a"), outgoing("a")],
+            &[],
+            None,
+            resolver.clone(),
+            stats.clone()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn unimplemented_outgoings_refused() {
+        let rocks = toml::from_str::<OutgoingConfig>("name = \"rocks\"\ntype = \"Rocks\"").unwrap();
+        let router = |outgoings: Vec<OutgoingConfig>, rules: &[RuleConfig]| {
+            Router::from_cfg(
+                outgoings,
+                rules,
+                None,
+                Arc::new(Resolver::default()),
+                Arc::new(Stats::default()),
+            )
+        };
+        let to_rocks = RuleConfig {
+            outgoing: "rocks".to_string(),
+            ..Default::default()
+        };
+        // As a rule's target, or as the default
+        assert!(router(vec![outgoing("direct"), rocks.clone()], &[to_rocks]).is_err());
+        assert!(router(vec![rocks.clone(), outgoing("direct")], &[]).is_err());
+        // Configured but never routed to
+        assert!(router(vec![outgoing("direct"), rocks], &[]).is_ok());
+    }
+}
@@ -0,0 +1,43 @@
+//! The signals that ask the server to shut down
+
+use std::io;
+
+#[cfg(unix)]
+use tokio::signal::unix::{signal, Signal, SignalKind};
+
+/// SIGTERM and SIGINT, taken over from the default handlers for as long as
+/// this lives
+pub struct Signals {
+    #[cfg(unix)]
+    term: Signal,
+    #[cfg(unix)]
+    int: Signal,
+}
+
+impl Signals {
+    #[cfg(unix)]
+    pub fn new() -> io::Result<Self> {
+        Ok(Signals {
+            term: signal(SignalKind::terminate())?,
+            int: signal(SignalKind::interrupt())?,
+        })
+    }
+    #[cfg(not(unix))]
+    pub fn new() -> io::Result<Self> {
+        Ok(Signals {})
+    }
+
+    /// Waits for the next signal; returns its name
+    #[cfg(unix)]
+    pub async fn recv(&mut self) -> &'static str {
+        tokio::select! {
+            _ = self.term.recv() => "SIGTERM",
+            _ = self.int.recv() => "SIGINT",
+        }
+    }
+    #[cfg(not(unix))]
+    pub async fn recv(&mut self) -> &'static str {
+        let _ = tokio::signal::ctrl_c().await;
+        "Ctrl-C"
+    }
+}
@@ -0,0 +1,383 @@
+use std::fmt::Display;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use log::{debug, log_enabled, Level};
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+
+use crate::config::{OutgoingConfig, SocketConfig};
+use crate::error::Error;
+
+/// Pending Fast Open requests a listener queues
+#[cfg(target_os = "linux")]
+const FAST_OPEN_QUEUE: libc::c_int = 256;
+const LISTEN_BACKLOG: i32 = 1024;
+
+#[cfg(target_os = "linux")]
+fn tcp_opt(socket: &Socket, opt: libc::c_int) -> io::Result<libc::c_int> {
+    use std::os::unix::io::AsRawFd;
+    let mut value: libc::c_int = 0;
+    let mut len = std::mem::size_of_val(&value) as libc::socklen_t;
+    let r = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            opt,
+            &mut value as *mut _ as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if r != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(value)
+}
+
+#[cfg(target_os = "linux")]
+fn set_tcp_opt(socket: &Socket, opt: libc::c_int, value: libc::c_int) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let r = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            opt,
+            &value as *const _ as *const libc::c_void,
+            std::mem::size_of_val(&value) as libc::socklen_t,
+        )
+    };
+    if r != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Server side Fast Open takes a queue length, client side is a flag
+#[cfg(target_os = "linux")]
+fn fast_open_opt(listener: bool) -> (libc::c_int, libc::c_int) {
+    if listener {
+        (libc::TCP_FASTOPEN, FAST_OPEN_QUEUE)
+    } else {
+        (libc::TCP_FASTOPEN_CONNECT, 1)
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_fast_open(socket: &Socket, listener: bool) -> io::Result<()> {
+    let (opt, value) = fast_open_opt(listener);
+    set_tcp_opt(socket, opt, value)
+}
+#[cfg(not(target_os = "linux"))]
+fn set_fast_open(_socket: &Socket, _listener: bool) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "TCP Fast Open is only supported on Linux",
+    ))
+}
+
+#[cfg(target_os = "linux")]
+fn fast_open(socket: &Socket, listener: bool) -> io::Result<libc::c_int> {
+    tcp_opt(socket, fast_open_opt(listener).0)
+}
+#[cfg(not(target_os = "linux"))]
+fn fast_open(_socket: &Socket, _listener: bool) -> io::Result<i32> {
+    Ok(0)
+}
+
+/// The options that apply to any connected or listening socket
+fn apply(socket: &Socket, conf: &SocketConfig) -> io::Result<()> {
+    if let Some(nodelay) = conf.nodelay {
+        socket.set_tcp_nodelay(nodelay)?;
+    }
+    if conf.keepalive_idle.is_some()
+        || conf.keepalive_interval.is_some()
+        || conf.keepalive_count.is_some()
+    {
+        let mut keepalive = TcpKeepalive::new();
+        if let Some(idle) = conf.keepalive_idle {
+            keepalive = keepalive.with_time(Duration::from_secs(idle));
+        }
+        #[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
+        {
+            if let Some(interval) = conf.keepalive_interval {
+                keepalive = keepalive.with_interval(Duration::from_secs(interval));
+            }
+            if let Some(count) = conf.keepalive_count {
+                keepalive = keepalive.with_retries(count);
+            }
+        }
+        socket.set_tcp_keepalive(&keepalive)?;
+    }
+    if let Some(size) = conf.send_buffer {
+        socket.set_send_buffer_size(size)?;
+    }
+    if let Some(size) = conf.recv_buffer {
+        socket.set_recv_buffer_size(size)?;
+    }
+    Ok(())
+}
+
+/// Logs what the OS made of the options, which may differ from what was
+/// asked for (buffer sizes in particular).
+fn log_effective(socket: &Socket, listener: bool, what: impl Display) {
+    if !log_enabled!(Level::Debug) {
+        return;
+    }
+    fn show<T: std::fmt::Debug>(r: io::Result<T>) -> String {
+        r.map_or_else(|_| "?".to_string(), |v| format!("{:?}", v))
+    }
+    let mut opts = format!(
+        "nodelay={} keepalive={} sndbuf={} rcvbuf={} fast_open={}",
+        show(socket.tcp_nodelay()),
+        show(socket.keepalive()),
+        show(socket.send_buffer_size()),
+        show(socket.recv_buffer_size()),
+        show(fast_open(socket, listener)),
+    );
+    if socket.keepalive().unwrap_or(false) {
+        opts += &format!(" keepalive_idle={}", show(socket.tcp_keepalive_time()));
+        #[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
+        {
+            opts += &format!(
+                " keepalive_interval={} keepalive_count={}",
+                show(socket.tcp_keepalive_interval()),
+                show(socket.tcp_keepalive_retries()),
+            );
+        }
+    }
+    if matches!(socket.local_addr().map(|a| a.is_ipv6()), Ok(true)) {
+        opts += &format!(" only_v6={}", show(socket.only_v6()));
+    }
+    debug!("socket options of {}: {}", what, opts);
+}
+
+/// Binds a TCP listener with `conf` applied
+pub fn bind_listener(addr: SocketAddr, conf: &SocketConfig) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    // As tokio's TcpListener::bind does
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    if let (true, Some(only_v6)) = (addr.is_ipv6(), conf.only_v6) {
+        socket.set_only_v6(only_v6)?;
+    }
+    apply(&socket, conf)?;
+    if conf.fast_open {
+        set_fast_open(&socket, true)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(LISTEN_BACKLOG)?;
+    log_effective(&socket, true, format_args!("listener {}", addr));
+    TcpListener::from_std(socket.into())
+}
+
+/// Applies `conf` to an accepted connection. Most options are inherited
+/// from the listener on Linux, but not everywhere.
+pub fn apply_accepted(stream: &TcpStream, conf: &SocketConfig) -> io::Result<()> {
+    if *conf == SocketConfig::default() {
+        return Ok(());
+    }
+    let socket = SockRef::from(stream);
+    apply(&socket, conf)?;
+    if let Ok(peer) = stream.peer_addr() {
+        log_effective(&socket, false, format_args!("connection from {}", peer));
+    }
+    Ok(())
+}
+
+/// How the sockets of an outgoing are set up: a source address (taken in
+/// turn from a pool), an interface, a firewall mark and TCP options.
+#[derive(Debug)]
+pub struct SourceBinding {
+    addrs: Vec<IpAddr>,
+    /// Turns taken by IPv4 and IPv6 destinations, counted apart so each
+    /// family goes through all of its addresses
+    next: [AtomicUsize; 2],
+    interface: Option<String>,
+    fwmark: Option<u32>,
+    options: SocketConfig,
+}
+
+impl SourceBinding {
+    pub fn from_cfg(conf: &OutgoingConfig) -> Result<Self, Error> {
+        if cfg!(not(target_os = "linux"))
+            && (conf.bind_interface.is_some() || conf.fwmark.is_some())
+        {
+            Err(Error::from_description(
+                "bind_interface and fwmark are only supported on Linux",
+            ))?
+        }
+        Ok(SourceBinding {
+            addrs: conf
+                .bind_addr
+                .iter()
+                .map(|a| a.parse::<IpAddr>())
+                .collect::<Result<Vec<_>, _>>()?,
+            next: Default::default(),
+            interface: conf.bind_interface.clone(),
+            fwmark: conf.fwmark,
+            options: conf.socket.clone(),
+        })
+    }
+
+    fn is_empty(&self) -> bool {
+        self.addrs.is_empty()
+            && self.interface.is_none()
+            && self.fwmark.is_none()
+            && self.options == SocketConfig::default()
+    }
+
+    /// Whether a socket to `dest` can be bound, i.e. the pool (if any) has an
+    /// address of its family
+    pub fn can_reach(&self, dest: &SocketAddr) -> bool {
+        self.addrs.is_empty() || self.addrs.iter().any(|a| a.is_ipv4() == dest.is_ipv4())
+    }
+
+    /// The next pool address of the family of `dest`
+    fn source_for(&self, dest: &SocketAddr) -> Option<IpAddr> {
+        let candidates = self
+            .addrs
+            .iter()
+            .filter(|a| a.is_ipv4() == dest.is_ipv4())
+            .collect::<Vec<_>>();
+        if candidates.is_empty() {
+            return None;
+        }
+        let n = self.next[dest.is_ipv6() as usize].fetch_add(1, Ordering::Relaxed);
+        Some(*candidates[n % candidates.len()])
+    }
+
+    #[cfg(target_os = "linux")]
+    fn apply_linux(&self, socket: &Socket) -> io::Result<()> {
+        if let Some(interface) = &self.interface {
+            socket.bind_device(Some(interface.as_bytes()))?;
+        }
+        if let Some(mark) = self.fwmark {
+            socket.set_mark(mark)?;
+        }
+        Ok(())
+    }
+    #[cfg(not(target_os = "linux"))]
+    fn apply_linux(&self, _socket: &Socket) -> io::Result<()> {
+        Ok(())
+    }
+
+    pub async fn connect(&self, dest: SocketAddr) -> io::Result<TcpStream> {
+        if self.is_empty() {
+            return TcpStream::connect(dest).await;
+        }
+        let socket = Socket::new(Domain::for_address(dest), Type::STREAM, Some(Protocol::TCP))?;
+        socket.set_nonblocking(true)?;
+        self.apply_linux(&socket)?;
+        apply(&socket, &self.options)?;
+        if self.options.fast_open {
+            set_fast_open(&socket, false)?;
+        }
+        if let Some(ip) = self.source_for(&dest) {
+            debug!("binding connection to {} to {}", dest, ip);
+            socket.bind(&SocketAddr::new(ip, 0).into())?;
+        }
+        let stream = TcpSocket::from_std_stream(socket.into())
+            .connect(dest)
+            .await?;
+        log_effective(
+            &SockRef::from(&stream),
+            false,
+            format_args!("connection to {}", dest),
+        );
+        Ok(stream)
+    }
+}
+
+#[cfg(test)]
+mod test_sockopt {
+    use super::*;
+    use crate::incoming::ClientInfo;
+    use crate::outgoing::{get_outgoing, Outgoing, OutgoingEntry, OutgoingError};
+    use crate::req_addr::ReqAddr;
+    use crate::resolver::Resolver;
+    use crate::stats::Stats;
+    use std::sync::Arc;
+
+    fn direct(bind_addr: &[&str]) -> OutgoingConfig {
+        toml::from_str(&format!("type = \"Direct\"\nbind_addr = {:?}", bind_addr)).unwrap()
+    }
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn sources_rotate_per_family() {
+        let binding = SourceBinding::from_cfg(&direct(&[
+            "192.0.2.10",
+            "2001:db8::10",
+            "192.0.2.11",
+            "2001:db8::11",
+            "192.0.2.12",
+        ]))
+        .unwrap();
+        let v4 = addr("198.51.100.1:80");
+        let v6 = addr("[2001:db8:1::1]:80");
+        let sources = [v4, v4, v6, v4, v6, v4]
+            .iter()
+            .map(|dest| binding.source_for(dest).unwrap().to_string())
+            .collect::<Vec<_>>();
+        // Every address of a family in turn, whatever the other family took
+        assert_eq!(
+            sources,
+            [
+                "192.0.2.10",
+                "192.0.2.11",
+                "2001:db8::10",
+                "192.0.2.12",
+                "2001:db8::11",
+                "192.0.2.10",
+            ]
+        );
+        assert!(SourceBinding::from_cfg(&direct(&["192.0.2.10"]))
+            .unwrap()
+            .source_for(&v6)
+            .is_none());
+    }
+
+    #[test]
+    fn reach_by_family() {
+        let v4 = addr("198.51.100.1:80");
+        let v6 = addr("[2001:db8:1::1]:80");
+        let any = SourceBinding::from_cfg(&direct(&[])).unwrap();
+        assert!(any.can_reach(&v4) && any.can_reach(&v6));
+        let only_v4 = SourceBinding::from_cfg(&direct(&["192.0.2.10"])).unwrap();
+        assert!(only_v4.can_reach(&v4));
+        assert!(!only_v4.can_reach(&v6));
+        let only_v6 = SourceBinding::from_cfg(&direct(&["2001:db8::10"])).unwrap();
+        assert!(!only_v6.can_reach(&v4));
+        assert!(only_v6.can_reach(&v6));
+    }
+
+    #[tokio::test]
+    async fn no_source_of_the_family() {
+        let entry = OutgoingEntry::from_cfg(
+            direct(&["192.0.2.10"]),
+            Arc::new(Resolver::default()),
+            Arc::new(Stats::default()),
+        )
+        .unwrap();
+        let outgoing = get_outgoing(Arc::new(entry)).unwrap();
+        let res = outgoing
+            .process_request(
+                ReqAddr::IP(addr("[2001:db8:1::1]:80")),
+                ClientInfo::default(),
+            )
+            .await;
+        match res {
+            Err(OutgoingError::NetworkUnreachable(e)) => {
+                assert!(e.to_string().contains("no bind_addr"), "{}", e)
+            }
+            Err(e) => panic!("{}", e),
+            Ok(_) => panic!("connected"),
+        }
+    }
+}
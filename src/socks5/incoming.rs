@@ -1,32 +1,64 @@
 use log::{debug, info};
+use std::collections::HashMap;
 use std::convert::TryFrom;
+use std::future::Future;
 use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
 
-use crate::incoming::{Incoming, IncomingClient};
+use crate::incoming::{Command, Incoming, IncomingClient};
+use crate::listener::{IncomingStream, Listener, StreamAddr};
 use crate::outgoing::OutgoingError;
+use crate::proxy_protocol::{self, ProxyProtocol};
 use crate::socks5::{
-    Socks5AddrType, Socks5Error, SOCKS5_CMD_CONNECT, SOCKS5_NO_ACCEPTABLE_METHOD, SOCKS5_NO_AUTH,
-    SOCKS5_PROTOCOL,
+    Socks5AddrType, Socks5Error, SOCKS5_CMD_CONNECT, SOCKS5_CMD_RESOLVE, SOCKS5_CMD_RESOLVE_PTR,
+    SOCKS5_NO_ACCEPTABLE_METHOD, SOCKS5_NO_AUTH, SOCKS5_PROTOCOL, SOCKS5_USER_PASS,
+    SOCKS5_USER_PASS_VERSION,
 };
-use crate::{config::CfgAddr, error::Error, req_addr::ReqAddr, StandardFuture};
+use crate::{error::Error, req_addr::ReqAddr, StandardFuture};
 
 #[derive(Debug)]
 pub(crate) struct Socks5Incoming {
-    listen_addr: SocketAddr,
-    listener: TcpListener,
+    listen_addr: StreamAddr,
+    listener: Listener,
+    users: Option<Arc<HashMap<String, String>>>,
+    proxy: Option<Arc<ProxyProtocol>>,
+    handshake_timeout: Option<Duration>,
 }
 
 pub struct Socks5Connected {
-    _local_addr: SocketAddr,
-    _remote_addr: SocketAddr,
-    stream: Option<TcpStream>,
+    _local_addr: StreamAddr,
+    _remote_addr: StreamAddr,
+    stream: Option<IncomingStream>,
+    users: Option<Arc<HashMap<String, String>>>,
+    proxy: Option<Arc<ProxyProtocol>>,
+    handshake_timeout: Option<Duration>,
+    user: Option<String>,
 }
 impl IncomingClient for Socks5Connected {
-    type Connection = TcpStream;
-    fn next_request<'a>(&'a mut self) -> StandardFuture<'a, ReqAddr, Error> {
+    type Connection = IncomingStream;
+    fn user(&self) -> Option<&str> {
+        self.user.as_deref()
+    }
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        match self._remote_addr {
+            StreamAddr::Ip(addr) => Some(addr),
+            StreamAddr::Unix(_) => None,
+        }
+    }
+    fn local_addr(&self) -> Option<SocketAddr> {
+        match self._local_addr {
+            StreamAddr::Ip(addr) => Some(addr),
+            StreamAddr::Unix(_) => None,
+        }
+    }
+    fn is_tcp(&self) -> bool {
+        matches!(self.stream, Some(IncomingStream::Tcp(_)))
+    }
+    fn next_request<'a>(&'a mut self) -> StandardFuture<'a, (Command, ReqAddr), Error> {
         Box::pin(async move {
+            self.read_proxy_header().await?;
             self.authenticate_client().await?;
             self.get_request().await
         })
@@ -35,12 +67,16 @@ impl IncomingClient for Socks5Connected {
         let reason = self.get_reason(&err);
         Box::pin(async move { self.send_final_response(reason, req).await })
     }
+    fn resolved(mut self, answer: ReqAddr) -> StandardFuture<'static, (), Error> {
+        Box::pin(async move { self.send_final_response(Socks5Error::Success, answer).await })
+    }
     fn ready_for_connect<'a>(
         &'a mut self,
-        req: ReqAddr,
+        bound: ReqAddr,
     ) -> StandardFuture<'a, Self::Connection, Error> {
         Box::pin(async move {
-            self.send_final_response(Socks5Error::Success, req).await?;
+            self.send_final_response(Socks5Error::Success, bound)
+                .await?;
             let stream = self.stream.take().unwrap();
             Ok(stream)
         })
@@ -55,30 +91,63 @@ impl Incoming for Socks5Incoming {
 }
 
 impl Socks5Incoming {
-    pub async fn from_cfg(conf: CfgAddr) -> Result<Self, Error> {
-        let listen_addr = conf.into_addr()?;
+    pub fn new(
+        listener: Listener,
+        users: Option<Arc<HashMap<String, String>>>,
+        proxy: Option<Arc<ProxyProtocol>>,
+        handshake_timeout: Option<Duration>,
+    ) -> Result<Self, Error> {
+        let listen_addr = listener.local_addr()?;
+        info!("socks5 listening at {}", listen_addr);
         Ok(Socks5Incoming {
             listen_addr,
-            listener: TcpListener::bind(listen_addr).await?,
+            listener,
+            users,
+            proxy,
+            handshake_timeout,
         })
     }
-    async fn next_client_impl<'a>(&'a mut self) -> Result<Socks5Connected, Error> {
+    async fn next_client_impl(&mut self) -> Result<Socks5Connected, Error> {
         let (stream, incoming_addr) = self.listener.accept().await?;
         info!("incoming!");
+        // The listener may be bound to a wildcard address
+        let local_addr = match &stream {
+            IncomingStream::Tcp(s) => s.local_addr().map(StreamAddr::Ip).ok(),
+            #[cfg(unix)]
+            IncomingStream::Unix(_) => None,
+        };
         let st = Socks5Connected {
-            _local_addr: self.listen_addr,
+            _local_addr: local_addr.unwrap_or_else(|| self.listen_addr.clone()),
             _remote_addr: incoming_addr,
             stream: Some(stream),
+            users: self.users.clone(),
+            proxy: self.proxy.clone(),
+            handshake_timeout: self.handshake_timeout,
+            user: None,
         };
         Ok(st)
     }
 }
 
+/// Gives up on a client that stalls the handshake
+async fn timed<T, E: Into<Error>>(
+    timeout: Option<Duration>,
+    io: impl Future<Output = Result<T, E>>,
+) -> Result<T, Error> {
+    match timeout {
+        Some(t) => match tokio::time::timeout(t, io).await {
+            Ok(res) => res.map_err(Into::into),
+            Err(_) => Err(Error::HandshakeTimeout),
+        },
+        None => io.await.map_err(Into::into),
+    }
+}
+
 impl Socks5Connected {
     fn get_reason(&self, err: &OutgoingError) -> Socks5Error {
         match err {
             OutgoingError::GeneralFailure(..) => Socks5Error::GeneralProxyFailure,
-            // OutgoingError::ConnectionNotAllowed(..) => Socks5Error::ConnectionNotAllowed,
+            OutgoingError::ConnectionNotAllowed(..) => Socks5Error::ConnectionNotAllowed,
             OutgoingError::NetworkUnreachable(..) => Socks5Error::NetworkUnreachable,
             OutgoingError::HostUnreachable(..) => Socks5Error::HostUnreachable,
             OutgoingError::ConnectionRefused(..) => Socks5Error::ConnectionRefused,
@@ -88,16 +157,35 @@ impl Socks5Connected {
     }
 
     async fn read_exact<'a>(&'a mut self, buf: &'a mut [u8]) -> Result<usize, Error> {
-        match self.stream.as_mut() {
-            Some(stream) => Ok(stream.read_exact(buf).await?),
-            None => Err(Error::NotConnected),
-        }
+        let timeout = self.handshake_timeout;
+        let stream = self.stream.as_mut().ok_or(Error::NotConnected)?;
+        timed(timeout, stream.read_exact(buf)).await
     }
     async fn write_all(&mut self, buf: &[u8]) -> Result<(), Error> {
-        match self.stream.as_mut() {
-            Some(stream) => Ok(stream.write_all(buf).await?),
-            None => Err(Error::NotConnected),
+        let timeout = self.handshake_timeout;
+        let stream = self.stream.as_mut().ok_or(Error::NotConnected)?;
+        timed(timeout, stream.write_all(buf)).await
+    }
+
+    /// Takes the client and local addresses from a PROXY protocol header,
+    /// when the peer is trusted to send one.
+    async fn read_proxy_header(&mut self) -> Result<(), Error> {
+        let trusted = match (&self.proxy, &self._remote_addr) {
+            (Some(proxy), StreamAddr::Ip(addr)) => proxy.trusts(addr.ip()),
+            (Some(_), StreamAddr::Unix(_)) => true,
+            (None, _) => false,
+        };
+        if !trusted {
+            return Ok(());
         }
+        let timeout = self.handshake_timeout;
+        let stream = self.stream.as_mut().ok_or(Error::NotConnected)?;
+        if let Some((src, dst)) = timed(timeout, proxy_protocol::read_header(stream)).await? {
+            info!("{} is proxying for {}", self._remote_addr, src);
+            self._remote_addr = StreamAddr::Ip(src);
+            self._local_addr = StreamAddr::Ip(dst);
+        }
+        Ok(())
     }
 
     async fn authenticate_client(&mut self) -> Result<(), Error> {
@@ -116,22 +204,64 @@ impl Socks5Connected {
         self.read_exact(&mut buf[0..num_auth_methods as usize])
             .await?;
         let authenticate_methods = &mut buf[0..num_auth_methods as usize];
-        if !authenticate_methods.contains(&SOCKS5_NO_AUTH) {
+        let method = if self.users.is_some() {
+            SOCKS5_USER_PASS
+        } else {
+            SOCKS5_NO_AUTH
+        };
+        if !authenticate_methods.contains(&method) {
             self.write_all(&[SOCKS5_PROTOCOL, SOCKS5_NO_ACCEPTABLE_METHOD])
                 .await?;
             Err(Error::from_description("No supported method given"))?;
         }
+        self.write_all(&[SOCKS5_PROTOCOL, method]).await?;
+        info!("wrote auth response");
+        if method == SOCKS5_USER_PASS {
+            self.authenticate_user_pass().await?;
+        }
         info!(
             "client authenticate successfully ({} -> {})",
             self._remote_addr, self._local_addr,
         );
-        self.write_all(&[SOCKS5_PROTOCOL, SOCKS5_NO_AUTH]).await?;
-        info!("wrote auth response");
         Ok(())
     }
 
-    pub async fn get_request(&mut self) -> Result<ReqAddr, Error> {
-        let mut buf = [0; 255];
+    /// Username/password sub-negotiation (RFC 1929)
+    async fn authenticate_user_pass(&mut self) -> Result<(), Error> {
+        let mut buf = [0u8; 256];
+        self.read_exact(&mut buf[0..2]).await?;
+        if buf[0] != SOCKS5_USER_PASS_VERSION {
+            Err(Error::from_description(&format!(
+                "Unknown username/password version - {}",
+                buf[0]
+            )))?;
+        }
+        let ulen = buf[1] as usize;
+        self.read_exact(&mut buf[0..ulen + 1]).await?;
+        let user = std::str::from_utf8(&buf[0..ulen])?.to_string();
+        let plen = buf[ulen] as usize;
+        self.read_exact(&mut buf[0..plen]).await?;
+        let pass = std::str::from_utf8(&buf[0..plen])?;
+        let valid = self
+            .users
+            .as_ref()
+            .and_then(|users| users.get(&user))
+            .is_some_and(|p| p == pass);
+        if !valid {
+            self.write_all(&[SOCKS5_USER_PASS_VERSION, 1]).await?;
+            Err(Error::from_description(&format!(
+                "authentication failed for user {}",
+                user
+            )))?;
+        }
+        self.write_all(&[SOCKS5_USER_PASS_VERSION, 0]).await?;
+        self.user = Some(user);
+        Ok(())
+    }
+
+    pub async fn get_request(&mut self) -> Result<(Command, ReqAddr), Error> {
+        // Room for the longest domain plus its port
+        let mut buf = [0; 257];
 
         self.read_exact(&mut buf[0..5]).await?;
 
@@ -143,11 +273,15 @@ impl Socks5Connected {
             )))?
         }
 
-        let cmd = buf[1];
-        if cmd != SOCKS5_CMD_CONNECT {
-            self.send_final_response(Socks5Error::CommandNotSupported, ReqAddr::default())
-                .await?;
-            Err(Error::from_description("req cmd is not connect"))?;
+        let cmd = match buf[1] {
+            SOCKS5_CMD_CONNECT => Command::Connect,
+            SOCKS5_CMD_RESOLVE => Command::Resolve,
+            SOCKS5_CMD_RESOLVE_PTR => Command::ResolvePtr,
+            _ => {
+                self.send_final_response(Socks5Error::CommandNotSupported, ReqAddr::default())
+                    .await?;
+                Err(Error::from_description("req cmd is not supported"))?
+            }
         };
 
         let atyp = buf[3];
@@ -168,55 +302,613 @@ impl Socks5Connected {
                 let addr = &mut buf[0..addr_len as usize + 2];
                 self.read_exact(addr).await?;
                 let r = ReqAddr::parse_domain(addr_len as usize, addr);
-                debug!("domain: {}", r.as_ref().unwrap());
+                if let Ok(domain) = &r {
+                    debug!("domain: {}", domain);
+                }
                 r
             }
-            Err(e) => {
-                self.send_final_response(Socks5Error::AddressTypeNotSupported, ReqAddr::default())
-                    .await?;
-                Err(e)
-            }
+            Err(e) => Err(e),
         };
-        if let Err(_) = addr {
+        if addr.is_err() {
             self.send_final_response(Socks5Error::AddressTypeNotSupported, ReqAddr::default())
                 .await?;
         }
-        addr
+        Ok((cmd, addr?))
     }
 
     async fn send_final_response(&mut self, err: Socks5Error, addr: ReqAddr) -> Result<(), Error> {
-        let mut resp = [0u8; 256 + 5];
-        resp[0] = 5;
-        resp[1] = err as u8;
-        resp[2] = 0;
-        let pos = match &addr {
-            ReqAddr::IP(SocketAddr::V4(ref a)) => {
-                resp[3] = Socks5AddrType::IPV4 as u8;
-                resp[4..8].copy_from_slice(&a.ip().octets());
-                8
+        let resp = encode_reply(err, &addr)?;
+        self.write_all(&resp).await?;
+        info!("final response sent code:{}", err);
+        Ok(())
+    }
+}
+
+/// The final reply to a request, with `addr` as BND.ADDR/BND.PORT
+fn encode_reply(err: Socks5Error, addr: &ReqAddr) -> Result<Vec<u8>, Error> {
+    let mut resp = Vec::with_capacity(22);
+    resp.extend_from_slice(&[SOCKS5_PROTOCOL, err as u8, 0]);
+    match addr {
+        ReqAddr::IP(SocketAddr::V4(a)) => {
+            resp.push(Socks5AddrType::IPV4 as u8);
+            resp.extend_from_slice(&a.ip().octets());
+        }
+        ReqAddr::IP(SocketAddr::V6(a)) => {
+            resp.push(Socks5AddrType::IPV6 as u8);
+            resp.extend_from_slice(&a.ip().octets());
+        }
+        ReqAddr::Domain(domain, _) => {
+            if domain.is_empty() || domain.len() > 255 {
+                Err(Error::from_description("domain can't be sent in a reply"))?
             }
-            ReqAddr::IP(SocketAddr::V6(ref a)) => {
-                resp[3] = Socks5AddrType::IPV6 as u8;
-                let mut pos = 4;
-                for &segment in a.ip().segments().iter() {
-                    resp[pos] = (segment >> 8) as u8;
-                    resp[pos + 1] = segment as u8;
-                    pos += 2;
+            resp.push(Socks5AddrType::DOMAIN as u8);
+            resp.push(domain.len() as u8);
+            resp.extend_from_slice(domain.as_bytes());
+        }
+    }
+    resp.extend_from_slice(&addr.port().to_be_bytes());
+    Ok(resp)
+}
+
+#[cfg(test)]
+mod test_socks5 {
+    use super::*;
+    use crate::client_manager::{handle_client, ClientContext, IncomingContext};
+    use crate::config::{HostsConfig, OutgoingConfig, SocketConfig};
+    use crate::connection::Connection;
+    use crate::ratelimit::IncomingLimit;
+    use crate::resolver::Resolver;
+    use crate::rules::Router;
+    use crate::stats::Stats;
+    use tokio::net::TcpStream;
+    use tokio::task::JoinHandle;
+
+    // Client bytes as captured from curl 8 (--socks5, --socks5-hostname and
+    // with --proxy-user alice:s3cret)
+    const GREETING_NO_AUTH: &str = "05020001";
+    const GREETING_USER_PASS: &str = "0503000102";
+    const AUTH_ALICE: &str = "0105616c69636506733363726574";
+    const CONNECT_V4: &str = "050100017f00000146a0";
+    const CONNECT_DOMAIN: &str = "050100030b6578616d706c652e636f6d0050";
+    const CONNECT_DOMAIN_8080: &str = "050100030b6578616d706c652e636f6d1f90";
+    const CONNECT_V6: &str = "050100040000000000000000000000000000000120fb";
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn users() -> Option<HashMap<String, String>> {
+        Some(HashMap::from([("alice".to_string(), "s3cret".to_string())]))
+    }
+
+    /// A fresh incoming on a local port, and the address to connect to
+    fn incoming(
+        users: Option<HashMap<String, String>>,
+        timeout: Option<Duration>,
+    ) -> (Socks5Incoming, SocketAddr) {
+        let listener =
+            Listener::bind_tcp("127.0.0.1:0".parse().unwrap(), SocketConfig::default()).unwrap();
+        let incoming = Socks5Incoming::new(listener, users.map(Arc::new), None, timeout).unwrap();
+        let addr = match &incoming.listen_addr {
+            StreamAddr::Ip(addr) => *addr,
+            StreamAddr::Unix(_) => unreachable!(),
+        };
+        (incoming, addr)
+    }
+
+    /// A context that sends every request to a single outgoing, given as
+    /// TOML, with `hosts` pinned in the resolver
+    fn context(outgoing: &str, hosts: &[(&str, &str)]) -> ClientContext {
+        let outgoing = toml::from_str::<OutgoingConfig>(outgoing).unwrap();
+        let resolver = Arc::new(
+            Resolver::from_cfg(Some(&HostsConfig {
+                file: None,
+                entries: hosts
+                    .iter()
+                    .map(|(name, ip)| (name.to_string(), ip.to_string()))
+                    .collect(),
+            }))
+            .unwrap(),
+        );
+        let stats = Arc::new(Stats::default());
+        let router =
+            Router::from_cfg(vec![outgoing], &[], None, resolver.clone(), stats.clone()).unwrap();
+        ClientContext {
+            resolver,
+            stats,
+            ..ClientContext::for_test(router)
+        }
+    }
+
+    const DIRECT: &str = "type = \"Direct\"";
+    /// For targets on loopback
+    const LAN: &str = "type = \"Direct\"\nacl = { block_private = false }";
+
+    /// Runs `handle_client` with `ctx` for the first client of a fresh
+    /// incoming. Returns the address to connect to and the handler.
+    fn serve(ctx: Arc<ClientContext>) -> (SocketAddr, JoinHandle<()>) {
+        let (mut incoming, addr) = incoming(None, None);
+        let inc_ctx = Arc::new(IncomingContext {
+            name: "test".to_string(),
+            outgoing: None,
+            rate_limit: IncomingLimit::from_cfg(&Default::default()),
+        });
+        let handler = tokio::spawn(async move {
+            let conn = incoming.next_client().await.unwrap();
+            handle_client(conn, ctx, inc_ctx).await
+        });
+        (addr, handler)
+    }
+
+    /// Sends `transcript` to a client handled with `ctx`, and returns
+    /// everything the server sent
+    async fn exchange(ctx: ClientContext, transcript: &[u8]) -> Vec<u8> {
+        let (addr, handler) = serve(Arc::new(ctx));
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(transcript).await.unwrap();
+        let mut out = vec![];
+        client.read_to_end(&mut out).await.unwrap();
+        handler.await.unwrap();
+        out
+    }
+
+    /// Feeds `transcript` to a fresh server side connection and runs the
+    /// handshake. Returns the connection, its outcome and the client end.
+    async fn handshake(
+        users: Option<HashMap<String, String>>,
+        transcript: &[u8],
+    ) -> (
+        Socks5Connected,
+        Result<(Command, ReqAddr), Error>,
+        TcpStream,
+    ) {
+        let (mut incoming, addr) = incoming(users, None);
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(transcript).await.unwrap();
+        let mut conn = incoming.next_client().await.unwrap();
+        let req = conn.next_request().await;
+        (conn, req, client)
+    }
+
+    /// Everything the server sent, once it is done with the connection
+    async fn server_output(conn: Socks5Connected, mut client: TcpStream) -> Vec<u8> {
+        drop(conn);
+        let mut out = vec![];
+        client.read_to_end(&mut out).await.unwrap();
+        out
+    }
+
+    fn addr(s: &str) -> ReqAddr {
+        ReqAddr::IP(s.parse().unwrap())
+    }
+
+    #[tokio::test]
+    async fn connect_v4() {
+        let transcript = hex(&(GREETING_NO_AUTH.to_string() + CONNECT_V4));
+        let (mut conn, req, client) = handshake(None, &transcript).await;
+        assert_eq!(req.unwrap(), (Command::Connect, addr("127.0.0.1:18080")));
+        assert_eq!(conn.user(), None);
+        conn.ready_for_connect(addr("10.0.0.2:40000"))
+            .await
+            .unwrap();
+        let out = server_output(conn, client).await;
+        assert_eq!(out, hex("0500050000010a0000029c40"));
+    }
+
+    #[tokio::test]
+    async fn connect_domain() {
+        let transcript = hex(&(GREETING_NO_AUTH.to_string() + CONNECT_DOMAIN));
+        let (mut conn, req, client) = handshake(None, &transcript).await;
+        assert_eq!(
+            req.unwrap(),
+            (
+                Command::Connect,
+                ReqAddr::Domain("example.com".to_string(), 80)
+            )
+        );
+        conn.ready_for_connect(addr("[2001:db8::1]:4321"))
+            .await
+            .unwrap();
+        let out = server_output(conn, client).await;
+        assert_eq!(out, hex("05000500000420010db800000000000000000000000110e1"));
+    }
+
+    #[tokio::test]
+    async fn connect_v6() {
+        let transcript = hex(&(GREETING_NO_AUTH.to_string() + CONNECT_V6));
+        let (_, req, _) = handshake(None, &transcript).await;
+        assert_eq!(req.unwrap(), (Command::Connect, addr("[::1]:8443")));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn connect_over_unix_socket() {
+        let path = std::env::temp_dir().join(format!("rocks-test-{}.sock", std::process::id()));
+        let conf = crate::config::UnixListenConfig {
+            path: path.to_str().unwrap().to_string(),
+            mode: Some("600".to_string()),
+            owner: None,
+            group: None,
+            allow_uid: vec![],
+            allow_gid: vec![],
+        };
+        let mut incoming =
+            Socks5Incoming::new(Listener::bind_unix(&conf).unwrap(), None, None, None).unwrap();
+        let mut client = tokio::net::UnixStream::connect(&path).await.unwrap();
+        let transcript = hex(&(GREETING_NO_AUTH.to_string() + CONNECT_V4));
+        client.write_all(&transcript).await.unwrap();
+        let mut conn = incoming.next_client().await.unwrap();
+        let req = conn.next_request().await.unwrap();
+        assert_eq!(req, (Command::Connect, addr("127.0.0.1:18080")));
+        // Unix clients get their reply like any other
+        let stream = conn
+            .ready_for_connect(addr("10.0.0.2:40000"))
+            .await
+            .unwrap();
+        assert!(stream.p_addr().is_err());
+        let mut out = [0; 12];
+        client.read_exact(&mut out).await.unwrap();
+        assert_eq!(out.to_vec(), hex("0500050000010a0000029c40"));
+        drop(incoming);
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn longest_domain() {
+        let domain = "a".repeat(255);
+        let mut transcript = hex("05010005010003ff");
+        transcript.extend_from_slice(domain.as_bytes());
+        transcript.extend_from_slice(&[0, 80]);
+        let (_, req, _) = handshake(None, &transcript).await;
+        assert_eq!(
+            req.unwrap(),
+            (Command::Connect, ReqAddr::Domain(domain, 80))
+        );
+    }
+
+    #[tokio::test]
+    async fn user_pass() {
+        let transcript = hex(&(GREETING_USER_PASS.to_string() + AUTH_ALICE + CONNECT_DOMAIN_8080));
+        let (conn, req, client) = handshake(users(), &transcript).await;
+        assert_eq!(
+            req.unwrap(),
+            (
+                Command::Connect,
+                ReqAddr::Domain("example.com".to_string(), 8080)
+            )
+        );
+        assert_eq!(conn.user(), Some("alice"));
+        assert_eq!(server_output(conn, client).await, hex("05020100"));
+    }
+
+    #[tokio::test]
+    async fn wrong_password() {
+        // alice / s3crex
+        let transcript = hex(&(GREETING_USER_PASS.to_string() + "0105616c69636506733363726578"));
+        let (conn, req, client) = handshake(users(), &transcript).await;
+        assert!(req.is_err());
+        assert_eq!(conn.user(), None);
+        assert_eq!(server_output(conn, client).await, hex("05020101"));
+    }
+
+    #[tokio::test]
+    async fn no_acceptable_method() {
+        // Only "no authentication" offered, but users are configured
+        let (conn, req, client) = handshake(users(), &hex("050100")).await;
+        assert!(req.is_err());
+        assert_eq!(server_output(conn, client).await, hex("05ff"));
+    }
+
+    #[tokio::test]
+    async fn unsupported_command() {
+        // BIND to 127.0.0.1:18080
+        let transcript = hex(&(GREETING_NO_AUTH.to_string() + "050200017f00000146a0"));
+        let (_conn, req, mut client) = handshake(None, &transcript).await;
+        assert!(req.is_err());
+        // The rest of the request is left unread, so the close may be a reset
+        let mut out = [0; 12];
+        client.read_exact(&mut out).await.unwrap();
+        assert_eq!(out.to_vec(), hex("0500050700017f0000010000"));
+    }
+
+    #[tokio::test]
+    async fn unsupported_address_type() {
+        let transcript = hex(&(GREETING_NO_AUTH.to_string() + "0501000500"));
+        let (conn, req, client) = handshake(None, &transcript).await;
+        assert!(req.is_err());
+        // A single reply, not one per failed check
+        let out = server_output(conn, client).await;
+        assert_eq!(out, hex("0500050800017f0000010000"));
+    }
+
+    #[tokio::test]
+    async fn stalled_handshake_times_out() {
+        let (mut incoming, addr) = incoming(None, Some(Duration::from_millis(100)));
+        // Half a greeting, then nothing
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(&hex("05")).await.unwrap();
+        let mut conn = incoming.next_client().await.unwrap();
+        match conn.next_request().await {
+            Err(Error::HandshakeTimeout) => {}
+            other => panic!("expected a timeout, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[tokio::test]
+    async fn trickling_handshake_hits_deadline() {
+        let deadline = Duration::from_millis(500);
+        let ctx = Arc::new(ClientContext {
+            handshake_deadline: Some(deadline),
+            ..context(DIRECT, &[])
+        });
+        let (addr, handler) = serve(ctx.clone());
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let started = std::time::Instant::now();
+        // A byte every step keeps the client from ever stalling
+        let step = Duration::from_millis(150);
+        let transcript = hex(&(GREETING_NO_AUTH.to_string() + "050100010a0000010050"));
+        let trickle = tokio::spawn(async move {
+            for byte in transcript {
+                if client.write_all(&[byte]).await.is_err() {
+                    break;
                 }
-                pos
-            }
-            ReqAddr::Domain(domain, _) => {
-                resp[3] = Socks5AddrType::DOMAIN as u8;
-                resp[4] = domain.len() as u8;
-                let pos = 5 + resp[4] as usize;
-                (&mut resp[5..pos]).copy_from_slice(domain.as_bytes());
-                pos
+                tokio::time::sleep(step).await;
             }
+            client
+        });
+        handler.await.unwrap();
+        let took = started.elapsed();
+        assert!(took >= deadline && took < deadline + step, "{:?}", took);
+        assert!(ctx.stats.summary().contains("handshake timeouts 1,"));
+        let mut client = trickle.await.unwrap();
+        let mut out = vec![];
+        // Closed without a reply to the request
+        let _ = client.read_to_end(&mut out).await;
+        assert_eq!(out, hex("0500"));
+    }
+
+    #[tokio::test]
+    async fn closing_ends_relays() {
+        let target = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        tokio::spawn(async move {
+            // Keeps the connection open without a word
+            let (_stream, _) = target.accept().await.unwrap();
+            std::future::pending::<()>().await
+        });
+        let (close_tx, closing) = tokio::sync::watch::channel(false);
+        let ctx = Arc::new(ClientContext {
+            closing,
+            ..context(LAN, &[])
+        });
+        let (addr, handler) = serve(ctx.clone());
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let mut transcript = hex(&(GREETING_NO_AUTH.to_string() + "050100017f000001"));
+        transcript.extend_from_slice(&target_addr.port().to_be_bytes());
+        client.write_all(&transcript).await.unwrap();
+        let mut out = [0; 12];
+        client.read_exact(&mut out).await.unwrap();
+        assert_eq!(&out[2..4], &hex("0500")[..]);
+        // Relaying, until told to close
+        close_tx.send(true).unwrap();
+        tokio::time::timeout(Duration::from_secs(1), handler)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(ctx
+            .stats
+            .summary()
+            .contains("connections active 0 closed 1 (idle timeouts 0, errors 0)"));
+    }
+
+    #[tokio::test]
+    async fn ignore_replies() {
+        let transcript = hex(&(GREETING_NO_AUTH.to_string() + CONNECT_V4));
+        let ignore = |reply| context(&format!("type = \"Ignore\"\nreply = \"{}\"", reply), &[]);
+        let out = exchange(ignore("ConnectionNotAllowed"), &transcript).await;
+        assert_eq!(out, hex("0500050200017f00000146a0"));
+        let out = exchange(ignore("HostUnreachable"), &transcript).await;
+        assert_eq!(out, hex("0500050400017f00000146a0"));
+    }
+
+    #[tokio::test]
+    async fn ignore_discards() {
+        let ctx = Arc::new(context("type = \"Ignore\"\nreply = \"Discard\"", &[]));
+        let (addr, handler) = serve(ctx.clone());
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let mut transcript = hex(&(GREETING_NO_AUTH.to_string() + CONNECT_V4));
+        transcript.extend_from_slice(b"GET / HTTP/1.0\r\n\r\n");
+        client.write_all(&transcript).await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut out = vec![];
+        client.read_to_end(&mut out).await.unwrap();
+        handler.await.unwrap();
+        // Success, then nothing back
+        assert_eq!(&out[..4], &hex("05000500")[..]);
+        assert_eq!(out.len(), 12);
+        let summary = ctx.stats.summary();
+        assert!(
+            summary.contains("bytes up 18 down 0, ignored 1"),
+            "{}",
+            summary
+        );
+    }
+
+    #[tokio::test]
+    async fn private_blocked_by_default() {
+        for connect in [CONNECT_V4, "050100010a0000010050"] {
+            let transcript = hex(&(GREETING_NO_AUTH.to_string() + connect));
+            let out = exchange(context(DIRECT, &[]), &transcript).await;
+            assert_eq!(out[..4], hex("05000502"), "{}", connect);
+        }
+    }
+
+    #[tokio::test]
+    async fn acl_applies_to_resolve() {
+        let acl = "block_private = true\ndeny_domain = [\"internal.example\"]";
+        let hosts = [
+            ("nas.example", "10.0.0.1"),
+            ("public.example", "192.0.2.1"),
+            ("db.internal.example", "192.0.2.2"),
+        ];
+        let direct = format!("{}\n[acl]\n{}", DIRECT, acl);
+        let serve = |transcript: Vec<u8>| {
+            let ctx = context(&direct, &hosts);
+            async move { exchange(ctx, &transcript).await }
         };
-        resp[pos] = (addr.port() >> 8) as u8;
-        resp[pos + 1] = addr.port() as u8;
-        self.write_all(&resp[0..pos + 2]).await?;
-        info!("final response sent code:{}", err);
-        Ok(())
+        let resolve = |domain: &str| {
+            let mut t = hex(&(GREETING_NO_AUTH.to_string() + "05f00003"));
+            t.push(domain.len() as u8);
+            t.extend_from_slice(domain.as_bytes());
+            t.extend_from_slice(&[0, 0]);
+            t
+        };
+        // Connection not allowed: denied domain, and a private address
+        let out = serve(resolve("db.internal.example")).await;
+        assert_eq!(out[..4], hex("05000502"));
+        let out = serve(resolve("nas.example")).await;
+        assert_eq!(out[..4], hex("05000502"));
+        let out = serve(resolve("public.example")).await;
+        assert_eq!(out, hex("050005000001c00002010000"));
+        // RESOLVE_PTR of 10.0.0.1, refused before any lookup
+        let ptr = hex(&(GREETING_NO_AUTH.to_string() + "05f100010a0000010000"));
+        let out = serve(ptr).await;
+        assert_eq!(out[..4], hex("05000502"));
+    }
+
+    #[tokio::test]
+    async fn lookups_skip_geoip_rules() {
+        use crate::config::{GeoIpConfig, RuleConfig};
+        use crate::geoip::test_geoip::{temp_path, write_country_db};
+
+        let country_db = temp_path("socks5-country");
+        write_country_db(&country_db, &[("192.0.2.0/24", "NZ")]);
+        #[derive(serde_derive::Deserialize)]
+        struct Tables {
+            outgoing: Vec<OutgoingConfig>,
+            rule: Vec<RuleConfig>,
+        }
+        let tables = toml::from_str::<Tables>(
+            r#"
+            [[outgoing]]
+            name = "direct"
+            type = "Direct"
+
+            [[outgoing]]
+            name = "block"
+            type = "Ignore"
+
+            [[rule]]
+            geoip_country = ["NZ"]
+            outgoing = "direct"
+
+            [[rule]]
+            domain_suffix = ["example"]
+            outgoing = "block"
+            "#,
+        )
+        .unwrap();
+        let resolver = Arc::new(
+            Resolver::from_cfg(Some(&HostsConfig {
+                file: None,
+                entries: [("nz.example".to_string(), "192.0.2.1".to_string())]
+                    .into_iter()
+                    .collect(),
+            }))
+            .unwrap(),
+        );
+        let router = Router::from_cfg(
+            tables.outgoing,
+            &tables.rule,
+            Some(&GeoIpConfig {
+                country_db: Some(country_db.clone()),
+                asn_db: None,
+                reload_interval: 60,
+            }),
+            resolver.clone(),
+            Arc::new(Stats::default()),
+        )
+        .unwrap();
+        std::fs::remove_file(&country_db).unwrap();
+        let ctx = ClientContext {
+            resolver: resolver.clone(),
+            ..ClientContext::for_test(router)
+        };
+        // RESOLVE of nz.example: the GeoIP rule is passed over, so the
+        // domain rule refuses it without a lookup
+        let transcript =
+            hex(&(GREETING_NO_AUTH.to_string() + "05f000030a6e7a2e6578616d706c650000"));
+        let out = exchange(ctx, &transcript).await;
+        assert_eq!(out[..4], hex("05000502"));
+        assert_eq!(resolver.lookups(), 0);
+    }
+
+    #[tokio::test]
+    async fn resolve() {
+        let transcript =
+            hex(&(GREETING_NO_AUTH.to_string() + "05f000030b6578616d706c652e636f6d0000"));
+        let (conn, req, mut client) = handshake(None, &transcript).await;
+        assert_eq!(
+            req.unwrap(),
+            (
+                Command::Resolve,
+                ReqAddr::Domain("example.com".to_string(), 0)
+            )
+        );
+        conn.resolved(addr("93.184.216.34:0")).await.unwrap();
+        let mut out = vec![];
+        client.read_to_end(&mut out).await.unwrap();
+        assert_eq!(out, hex("0500050000015db8d8220000"));
+    }
+
+    #[tokio::test]
+    async fn resolve_ptr() {
+        let transcript = hex(&(GREETING_NO_AUTH.to_string() + "05f100017f0000010000"));
+        let (conn, req, mut client) = handshake(None, &transcript).await;
+        assert_eq!(req.unwrap(), (Command::ResolvePtr, addr("127.0.0.1:0")));
+        conn.resolved(ReqAddr::Domain("localhost".to_string(), 0))
+            .await
+            .unwrap();
+        let mut out = vec![];
+        client.read_to_end(&mut out).await.unwrap();
+        assert_eq!(out, hex("050005000003096c6f63616c686f73740000"));
+    }
+
+    #[tokio::test]
+    async fn abort_not_allowed() {
+        let transcript = hex(&(GREETING_NO_AUTH.to_string() + CONNECT_V4));
+        let (conn, req, mut client) = handshake(None, &transcript).await;
+        let (_, r) = req.unwrap();
+        let err = OutgoingError::ConnectionNotAllowed(Error::from_description("denied"));
+        conn.abort(err, r).await.unwrap();
+        let mut out = vec![];
+        client.read_to_end(&mut out).await.unwrap();
+        assert_eq!(out, hex("0500050200017f00000146a0"));
+    }
+
+    #[test]
+    fn parse_addresses() {
+        assert_eq!(
+            ReqAddr::parse_address_v4(&hex("7f00000146a0")).unwrap(),
+            addr("127.0.0.1:18080")
+        );
+        assert_eq!(
+            ReqAddr::parse_address_v6(&hex("0000000000000000000000000000000120fb")).unwrap(),
+            addr("[::1]:8443")
+        );
+        assert!(ReqAddr::parse_address_v4(&hex("7f000001")).is_err());
+        assert!(ReqAddr::parse_domain(0, &hex("0050")).is_err());
+    }
+
+    #[test]
+    fn encode_replies() {
+        assert_eq!(
+            encode_reply(Socks5Error::HostUnreachable, &addr("192.168.1.1:1080")).unwrap(),
+            hex("05040001c0a801010438")
+        );
+        assert!(encode_reply(Socks5Error::Success, &ReqAddr::Domain("a".repeat(256), 0)).is_err());
     }
 }
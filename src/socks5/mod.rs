@@ -11,7 +11,7 @@ const SOCKS5_PROTOCOL: u8 = 5;
 pub enum Socks5Error {
     Success = 0,
     GeneralProxyFailure = 1,
-    //ConnectionNotAllowed = 2,
+    ConnectionNotAllowed = 2,
     NetworkUnreachable = 3,
     HostUnreachable = 4,
     ConnectionRefused = 5,
@@ -22,8 +22,11 @@ pub enum Socks5Error {
 }
 
 const SOCKS5_NO_AUTH: u8 = 0;
+const SOCKS5_USER_PASS: u8 = 2;
+const SOCKS5_USER_PASS_VERSION: u8 = 1;
 const SOCKS5_NO_ACCEPTABLE_METHOD: u8 = 0xff;
 
+#[allow(clippy::upper_case_acronyms)]
 pub enum Socks5AddrType {
     IPV4 = 1,
     IPV6 = 4,
@@ -42,3 +45,7 @@ impl TryFrom<u8> for Socks5AddrType {
 }
 
 const SOCKS5_CMD_CONNECT: u8 = 1;
+/// Tor extension: forward lookup, answered in BND.ADDR
+const SOCKS5_CMD_RESOLVE: u8 = 0xf0;
+/// Tor extension: reverse lookup, answered in BND.ADDR
+const SOCKS5_CMD_RESOLVE_PTR: u8 = 0xf1;
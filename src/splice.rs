@@ -0,0 +1,102 @@
+//! Relaying between two TCP sockets with splice(2): the data goes from one
+//! socket into a pipe and from there into the other socket, without being
+//! copied through user space.
+
+use std::io;
+use std::net::Shutdown;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::Arc;
+
+use log::debug;
+use tokio::io::Interest;
+use tokio::net::TcpStream;
+
+use crate::connection::{Direction, Progress};
+use crate::ratelimit::TokenBucket;
+
+/// At most one pipe's worth (its default capacity) at a time, so the pipe is
+/// always empty before the next read
+const SPLICE_CHUNK: usize = 64 * 1024;
+
+#[derive(Debug)]
+pub struct Pipe {
+    read: OwnedFd,
+    write: OwnedFd,
+}
+
+impl Pipe {
+    pub fn new() -> io::Result<Self> {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(unsafe {
+            Pipe {
+                read: OwnedFd::from_raw_fd(fds[0]),
+                write: OwnedFd::from_raw_fd(fds[1]),
+            }
+        })
+    }
+}
+
+fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+    let n = unsafe {
+        libc::splice(
+            from,
+            std::ptr::null_mut(),
+            to,
+            std::ptr::null_mut(),
+            len,
+            libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+        )
+    };
+    if n < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(n as usize)
+    }
+}
+
+/// Moves data from `src` to `dst` until `src` hits EOF, then shuts down
+/// the write side of `dst`. Counts and limits like the buffered copy.
+pub async fn copy_half(
+    src: &TcpStream,
+    dst: &TcpStream,
+    pipe: Pipe,
+    progress: &Progress,
+    dir: Direction,
+    buckets: &[Arc<TokenBucket>],
+) -> io::Result<()> {
+    loop {
+        src.readable().await?;
+        let n = match src.try_io(Interest::READABLE, || {
+            splice(src.as_raw_fd(), pipe.write.as_raw_fd(), SPLICE_CHUNK)
+        }) {
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(e) => return Err(e),
+        };
+        if n == 0 {
+            // The peer may already be gone, which is no reason to fail
+            if let Err(e) = socket2::SockRef::from(dst).shutdown(Shutdown::Write) {
+                debug!("shutdown after EOF: {}", e);
+            }
+            return Ok(());
+        }
+        for bucket in buckets {
+            bucket.take(n).await;
+        }
+        let mut left = n;
+        while left > 0 {
+            dst.writable().await?;
+            match dst.try_io(Interest::WRITABLE, || {
+                splice(pipe.read.as_raw_fd(), dst.as_raw_fd(), left)
+            }) {
+                Ok(m) => left -= m,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        progress.transferred(dir, n);
+    }
+}
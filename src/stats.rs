@@ -0,0 +1,363 @@
+//! Accounting of relayed connections: a record for each one when it closes,
+//! and totals over all of them. Other accounting (quotas) subscribes to the
+//! records instead of being wired into the relay.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use log::info;
+
+use crate::bufpool::BufferPool;
+use crate::req_addr::ReqAddr;
+
+/// Destinations whose `Ignore` hits are counted; beyond them the least hit
+/// one makes room for the next
+const MAX_IGNORED_DESTINATIONS: usize = 1024;
+/// Most ignored destinations shown in the summary
+const TOP_IGNORED: usize = 5;
+
+/// Why a relayed connection ended
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CloseReason {
+    /// Both directions reached EOF
+    Done,
+    IdleTimeout,
+    /// The user's traffic quota ran out
+    QuotaExceeded,
+    /// Still open when the server shut down
+    Shutdown,
+    Error(String),
+}
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CloseReason::Done => write!(f, "done"),
+            CloseReason::IdleTimeout => write!(f, "idle timeout"),
+            CloseReason::QuotaExceeded => write!(f, "quota used up"),
+            CloseReason::Shutdown => write!(f, "server shutting down"),
+            CloseReason::Error(e) => write!(f, "error: {}", e),
+        }
+    }
+}
+
+/// What the relay saw of a connection
+#[derive(Debug)]
+pub struct Transfer {
+    /// Bytes from the client to the target
+    pub up: u64,
+    /// Bytes from the target to the client
+    pub down: u64,
+    /// When the first byte from the target was passed on
+    pub first_down: Option<Instant>,
+    pub close: CloseReason,
+}
+
+/// One relayed connection, from the request to the close
+#[derive(Debug)]
+pub struct ConnRecord {
+    pub incoming: String,
+    pub outgoing: String,
+    pub user: Option<String>,
+    pub client: Option<SocketAddr>,
+    pub target: ReqAddr,
+    pub up: u64,
+    pub down: u64,
+    pub duration: Duration,
+    /// From the request to the first byte from the target
+    pub ttfb: Option<Duration>,
+    pub close: CloseReason,
+}
+
+impl ConnRecord {
+    pub fn new(
+        incoming: &str,
+        outgoing: &str,
+        user: Option<&str>,
+        client: Option<SocketAddr>,
+        target: ReqAddr,
+        started: Instant,
+        transfer: Transfer,
+    ) -> Self {
+        ConnRecord {
+            incoming: incoming.to_string(),
+            outgoing: outgoing.to_string(),
+            user: user.map(str::to_string),
+            client,
+            target,
+            up: transfer.up,
+            down: transfer.down,
+            duration: started.elapsed(),
+            ttfb: transfer.first_down.map(|t| t.duration_since(started)),
+            close: transfer.close,
+        }
+    }
+}
+
+impl fmt::Display for ConnRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} -> {} via {} (incoming {}, user {}): up {} down {} in {}ms, ttfb {}, {}",
+            self.client
+                .map_or("local client".to_string(), |a| a.to_string()),
+            self.target,
+            self.outgoing,
+            self.incoming,
+            self.user.as_deref().unwrap_or("-"),
+            self.up,
+            self.down,
+            self.duration.as_millis(),
+            self.ttfb
+                .map_or("-".to_string(), |t| format!("{}ms", t.as_millis())),
+            self.close
+        )
+    }
+}
+
+/// Told about the traffic of relayed connections as `Stats` counts it
+pub trait Subscriber: Send + Sync {
+    /// `bytes` more (both ways) went through a connection of `user`
+    fn transferred(&self, _user: Option<&str>, _bytes: u64) {}
+    /// A connection closed; its traffic was already `transferred`
+    fn closed(&self, _rec: &ConnRecord) {}
+}
+
+#[derive(Default)]
+struct Subscribers(RwLock<Vec<Arc<dyn Subscriber>>>);
+impl fmt::Debug for Subscribers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} subscribers", self.0.read().unwrap().len())
+    }
+}
+
+/// Totals over all connections since startup
+#[derive(Debug, Default)]
+pub struct Stats {
+    rejected: AtomicU64,
+    handshake_timeouts: AtomicU64,
+    requests: AtomicU64,
+    failed: AtomicU64,
+    active: AtomicU64,
+    closed: AtomicU64,
+    idle_timeouts: AtomicU64,
+    errors: AtomicU64,
+    bytes_up: AtomicU64,
+    bytes_down: AtomicU64,
+    /// Requests turned away or discarded by `Ignore` outgoings
+    ignored: AtomicU64,
+    /// Those requests by destination, for the most ignored ones
+    ignored_by_dest: Mutex<HashMap<String, u64>>,
+    subscribers: Subscribers,
+}
+
+impl Stats {
+    /// Passes every record to `subscriber` from now on
+    pub fn subscribe(&self, subscriber: Arc<dyn Subscriber>) {
+        self.subscribers.0.write().unwrap().push(subscriber);
+    }
+    /// A client was turned away by the connection limits
+    pub fn rejected(&self) {
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+    }
+    /// A client took too long with its handshake
+    pub fn handshake_timeout(&self) {
+        self.handshake_timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+    /// A request was received
+    pub fn request(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+    }
+    /// A request was turned down, or its connection could not be made
+    pub fn failed(&self) {
+        self.failed.fetch_add(1, Ordering::Relaxed);
+    }
+    /// A request for `dest` was routed to an `Ignore` outgoing; returns
+    /// how many that destination has had. Once `MAX_IGNORED_DESTINATIONS`
+    /// are counted, a new one takes over the least hit one along with its
+    /// count, so the counts are upper bounds but the busiest destinations
+    /// stay.
+    pub fn ignored(&self, dest: &ReqAddr) -> u64 {
+        self.ignored.fetch_add(1, Ordering::Relaxed);
+        let key = match dest {
+            ReqAddr::IP(addr) => addr.ip().to_string(),
+            ReqAddr::Domain(domain, _) => domain.trim_end_matches('.').to_ascii_lowercase(),
+        };
+        let mut by_dest = self.ignored_by_dest.lock().unwrap();
+        if !by_dest.contains_key(&key) && by_dest.len() >= MAX_IGNORED_DESTINATIONS {
+            let (least, hits) = by_dest
+                .iter()
+                .min_by_key(|(_, hits)| **hits)
+                .map(|(dest, hits)| (dest.clone(), *hits))
+                .unwrap();
+            by_dest.remove(&least);
+            by_dest.insert(key.clone(), hits);
+        }
+        let count = by_dest.entry(key).or_insert(0);
+        *count += 1;
+        *count
+    }
+    /// Passes traffic of a connection that is still open (or whose rest is
+    /// about to be recorded) to the subscribers
+    pub fn transferred(&self, user: Option<&str>, bytes: u64) {
+        if bytes == 0 {
+            return;
+        }
+        for subscriber in self.subscribers.0.read().unwrap().iter() {
+            subscriber.transferred(user, bytes);
+        }
+    }
+    /// A connection starts relaying
+    pub fn opened(&self) {
+        self.active.fetch_add(1, Ordering::Relaxed);
+    }
+    /// Adds the record of a connection that was `opened`, logs it and
+    /// hands it to the subscribers
+    pub fn record(&self, rec: &ConnRecord) {
+        info!("closed {}", rec);
+        self.active.fetch_sub(1, Ordering::Relaxed);
+        self.closed.fetch_add(1, Ordering::Relaxed);
+        self.bytes_up.fetch_add(rec.up, Ordering::Relaxed);
+        self.bytes_down.fetch_add(rec.down, Ordering::Relaxed);
+        match rec.close {
+            CloseReason::Done | CloseReason::QuotaExceeded | CloseReason::Shutdown => {}
+            CloseReason::IdleTimeout => {
+                self.idle_timeouts.fetch_add(1, Ordering::Relaxed);
+            }
+            CloseReason::Error(_) => {
+                self.errors.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        for subscriber in self.subscribers.0.read().unwrap().iter() {
+            subscriber.closed(rec);
+        }
+    }
+
+    pub fn summary(&self) -> String {
+        let get = |c: &AtomicU64| c.load(Ordering::Relaxed);
+        let mut top = self
+            .ignored_by_dest
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(dest, hits)| (*hits, dest.clone()))
+            .collect::<Vec<_>>();
+        top.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+        let top = top
+            .iter()
+            .take(TOP_IGNORED)
+            .map(|(hits, dest)| format!("{} {}", dest, hits))
+            .collect::<Vec<_>>();
+        format!(
+            "clients rejected {}, handshake timeouts {}, requests {} (failed {}), \
+             connections active {} closed {} (idle timeouts {}, errors {}), \
+             bytes up {} down {}, ignored {}{}",
+            get(&self.rejected),
+            get(&self.handshake_timeouts),
+            get(&self.requests),
+            get(&self.failed),
+            get(&self.active),
+            get(&self.closed),
+            get(&self.idle_timeouts),
+            get(&self.errors),
+            get(&self.bytes_up),
+            get(&self.bytes_down),
+            get(&self.ignored),
+            if top.is_empty() {
+                String::new()
+            } else {
+                format!(" ({})", top.join(", "))
+            }
+        )
+    }
+
+    /// Logs the totals, and the use of relay buffers, every `interval`
+    pub async fn log_summaries(self: Arc<Self>, interval: Duration, buffers: Arc<BufferPool>) {
+        let mut ticker = tokio::time::interval(interval);
+        // The first tick is immediate
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            info!("stats: {}; {}", self.summary(), buffers.summary());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_stats {
+    use super::*;
+
+    #[derive(Default)]
+    struct Bytes(AtomicU64);
+    impl Subscriber for Bytes {
+        fn transferred(&self, _user: Option<&str>, bytes: u64) {
+            self.0.fetch_add(bytes, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn summary_counts() {
+        let stats = Stats::default();
+        let bytes = Arc::new(Bytes::default());
+        stats.subscribe(bytes.clone());
+        let ads = ReqAddr::Domain("Ads.example.".to_string(), 443);
+        assert_eq!(stats.ignored(&ads), 1);
+        let ads = ReqAddr::Domain("ads.example".to_string(), 80);
+        assert_eq!(stats.ignored(&ads), 2);
+        assert_eq!(
+            stats.ignored(&ReqAddr::IP("192.0.2.1:80".parse().unwrap())),
+            1
+        );
+        stats.request();
+        stats.opened();
+        stats.transferred(Some("alice"), 30);
+        stats.record(&ConnRecord::new(
+            "default",
+            "direct",
+            Some("alice"),
+            None,
+            ReqAddr::Domain("example.com".to_string(), 443),
+            Instant::now(),
+            Transfer {
+                up: 10,
+                down: 20,
+                first_down: None,
+                close: CloseReason::IdleTimeout,
+            },
+        ));
+        assert_eq!(
+            stats.summary(),
+            "clients rejected 0, handshake timeouts 0, requests 1 (failed 0), \
+             connections active 0 closed 1 (idle timeouts 1, errors 0), \
+             bytes up 10 down 20, ignored 3 (ads.example 2, 192.0.2.1 1)"
+        );
+        assert_eq!(bytes.0.load(Ordering::Relaxed), 30);
+    }
+
+    #[test]
+    fn ignored_destinations_bounded() {
+        let stats = Stats::default();
+        let dest = |i: usize| ReqAddr::Domain(format!("{}.example", i), 443);
+        for i in 0..MAX_IGNORED_DESTINATIONS {
+            stats.ignored(&dest(i));
+        }
+        for _ in 0..3 {
+            stats.ignored(&dest(7));
+        }
+        // Takes over a destination with a single hit
+        assert_eq!(stats.ignored(&dest(MAX_IGNORED_DESTINATIONS)), 2);
+        assert_eq!(
+            stats.ignored_by_dest.lock().unwrap().len(),
+            MAX_IGNORED_DESTINATIONS
+        );
+        // Which single hit destination made room is up to the map
+        assert!(stats.summary().contains(&format!(
+            "ignored {} (7.example 4, {}.example 2, ",
+            MAX_IGNORED_DESTINATIONS + 4,
+            MAX_IGNORED_DESTINATIONS
+        )));
+    }
+}
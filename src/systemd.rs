@@ -0,0 +1,174 @@
+//! Socket activation: listening sockets handed over by systemd, see
+//! sd_listen_fds(3). Sockets are matched to incomings by their
+//! `FileDescriptorName=`.
+
+use std::collections::HashMap;
+#[cfg(unix)]
+use std::os::unix::io::{FromRawFd, OwnedFd, RawFd};
+
+#[cfg(unix)]
+use log::{error, info};
+
+use crate::error::Error;
+
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: RawFd = 3;
+/// The name systemd gives sockets without `FileDescriptorName=`
+const UNNAMED: &str = "unknown";
+
+#[cfg(unix)]
+pub type InheritedFd = OwnedFd;
+/// Nothing is ever inherited
+#[cfg(not(unix))]
+pub enum InheritedFd {}
+
+/// The sockets passed to this process, by name. Unnamed sockets are filed
+/// under `unknown`, as systemd does.
+pub type ListenFds = HashMap<String, Vec<InheritedFd>>;
+
+/// The descriptors and names announced by `LISTEN_PID`, `LISTEN_FDS` and
+/// `LISTEN_FDNAMES`, if they are meant for `own_pid`
+#[cfg(unix)]
+fn parse_listen_fds(
+    pid: Option<&str>,
+    count: Option<&str>,
+    names: Option<&str>,
+    own_pid: u32,
+) -> Result<Vec<(RawFd, String)>, Error> {
+    // Meant for another process
+    if pid.and_then(|p| p.parse::<u32>().ok()) != Some(own_pid) {
+        return Ok(vec![]);
+    }
+    let count = match count.map(|n| n.parse::<RawFd>()) {
+        Some(Ok(n)) if (0..=1024).contains(&n) => n,
+        Some(_) => Err(Error::from_description("invalid LISTEN_FDS"))?,
+        None => return Ok(vec![]),
+    };
+    let mut names = names.unwrap_or_default().split(':');
+    Ok((SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count)
+        .map(|fd| {
+            let name = names.next().filter(|n| !n.is_empty()).unwrap_or(UNNAMED);
+            (fd, name.to_string())
+        })
+        .collect())
+}
+
+/// Takes over the sockets announced in `LISTEN_FDS`. The variables are
+/// removed so that they are not passed on to child processes; changing the
+/// environment is only sound while no other thread runs, so this must be
+/// called before the runtime is started.
+#[cfg(unix)]
+pub fn take_listen_fds() -> ListenFds {
+    let var = |name| std::env::var(name).ok();
+    let (pid, count, names) = (var("LISTEN_PID"), var("LISTEN_FDS"), var("LISTEN_FDNAMES"));
+    for var in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        std::env::remove_var(var);
+    }
+    let announced = match parse_listen_fds(
+        pid.as_deref(),
+        count.as_deref(),
+        names.as_deref(),
+        std::process::id(),
+    ) {
+        Ok(announced) => announced,
+        Err(e) => {
+            error!("{}", e);
+            return ListenFds::new();
+        }
+    };
+    let mut fds = ListenFds::new();
+    for (fd, name) in announced {
+        unsafe {
+            libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+        }
+        info!("socket {} passed by systemd as {}", fd, name);
+        fds.entry(name)
+            .or_default()
+            .push(unsafe { OwnedFd::from_raw_fd(fd) });
+    }
+    fds
+}
+#[cfg(not(unix))]
+pub fn take_listen_fds() -> ListenFds {
+    ListenFds::new()
+}
+
+/// Hands unnamed sockets to the only incoming there is. With several
+/// incomings nothing says which one they are for, so that is an error.
+pub fn assign_unnamed<T>(
+    fds: &mut HashMap<String, Vec<T>>,
+    incomings: &[&str],
+) -> Result<(), Error> {
+    if incomings.contains(&UNNAMED) {
+        return Ok(());
+    }
+    let unnamed = match fds.remove(UNNAMED) {
+        Some(unnamed) => unnamed,
+        None => return Ok(()),
+    };
+    match incomings {
+        [only] => {
+            fds.entry(only.to_string()).or_default().extend(unnamed);
+            Ok(())
+        }
+        _ => Err(Error::from_description(
+            "systemd passed sockets without FileDescriptorName=, which is only \
+             allowed with a single incoming",
+        ))?,
+    }
+}
+
+#[cfg(test)]
+mod test_systemd {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn listen_fds() {
+        let parse = |pid, count, names| parse_listen_fds(pid, count, names, 42);
+        let fds = parse(Some("42"), Some("3"), Some("socks::dns")).unwrap();
+        assert_eq!(
+            fds,
+            [
+                (3, "socks".to_string()),
+                (4, UNNAMED.to_string()),
+                (5, "dns".to_string())
+            ]
+        );
+        // Fewer names than sockets
+        let fds = parse(Some("42"), Some("2"), None).unwrap();
+        assert_eq!(fds, [(3, UNNAMED.to_string()), (4, UNNAMED.to_string())]);
+        assert!(parse(Some("41"), Some("2"), None).unwrap().is_empty());
+        assert!(parse(None, Some("2"), None).unwrap().is_empty());
+        assert!(parse(Some("42"), None, None).unwrap().is_empty());
+        assert!(parse(Some("42"), Some("two"), None).is_err());
+        assert!(parse(Some("42"), Some("-1"), None).is_err());
+    }
+
+    #[test]
+    fn unnamed_sockets() {
+        let fds = |names: &[&str]| {
+            names
+                .iter()
+                .enumerate()
+                .fold(HashMap::<String, Vec<usize>>::new(), |mut m, (i, n)| {
+                    m.entry(n.to_string()).or_default().push(i);
+                    m
+                })
+        };
+        let mut one = fds(&[UNNAMED, "socks", UNNAMED]);
+        assign_unnamed(&mut one, &["socks"]).unwrap();
+        assert_eq!(one, HashMap::from([("socks".to_string(), vec![1, 0, 2])]));
+
+        let mut several = fds(&[UNNAMED]);
+        assert!(assign_unnamed(&mut several, &["socks", "http"]).is_err());
+        // Named sockets need no guessing
+        let mut named = fds(&["socks", "http"]);
+        assign_unnamed(&mut named, &["socks", "http"]).unwrap();
+        assert_eq!(named, fds(&["socks", "http"]));
+        // An incoming may really be called that
+        let mut literal = fds(&[UNNAMED]);
+        assign_unnamed(&mut literal, &[UNNAMED, "http"]).unwrap();
+        assert_eq!(literal, fds(&[UNNAMED]));
+    }
+}